@@ -1,43 +1,268 @@
-use crate::parse::{Function, ParseError};
-use eframe::egui::{Color32, Key, RichText, Vec2, Vec2b};
+mod worker;
+
+use crate::parse::{
+    Definition, EvalError, Function, Limits, Parameter, ParseError, SampleOptions, SampledCurve,
+    Span, Viewport,
+};
+use eframe::egui::ecolor::Hsva;
+use eframe::egui::text::LayoutJob;
+use eframe::egui::{Color32, Key, RichText, Stroke, TextFormat, Vec2, Vec2b};
 use eframe::{egui, Theme};
-use egui_plot::{Legend, Line, Plot, PlotPoint, PlotPoints};
+use egui_plot::{Legend, Line, LineStyle, Plot, PlotPoint, PlotPoints, VLine};
+use std::collections::HashMap;
+use std::sync::Arc;
+use worker::{Finished, Job, Worker};
 
-#[derive(Default)]
 pub struct GraphErBrain {
     zoom: Zoom,
     function_thing: Vec<FunctionInput>,
+    /// Samples the functions in the background.
+    worker: Worker,
+    /// The id of the last job given to the worker.
+    last_job: u64,
+    /// Limits every input is parsed within.
+    limits: Limits,
 }
 #[derive(Default)]
-struct FunctionInput(String);
+struct FunctionInput {
+    text: String,
+    /// The parsed function, kept until the text of any input changes.
+    cache: Option<ParsedInput>,
+    /// The last finished sampling, drawn until a newer one is done.
+    sampled: Option<Finished>,
+    /// The sampling asked for last, while the worker is still busy with it.
+    pending: Option<Job>,
+    /// The range of the slider, if the input defines a parameter.
+    slider: SliderRange,
+    /// The values of the function's variables other than the plotted one, set with sliders.
+    arguments: HashMap<String, f64>,
+    /// The ranges of the sliders for `arguments`.
+    argument_sliders: HashMap<String, SliderRange>,
+}
+
+struct ParsedInput {
+    /// The text the definition was parsed from.
+    text: String,
+    definition: Result<Definition, ParseError>,
+    /// The simplified definition shown in the legend, if the input is a valid function.
+    legend: Option<String>,
+}
+
+/// The values a parameter's slider goes between, and how far apart they are.
+struct SliderRange {
+    min: f64,
+    max: f64,
+    step: f64,
+}
+
+impl Default for SliderRange {
+    fn default() -> Self {
+        Self {
+            min: -10.,
+            max: 10.,
+            step: 0.1,
+        }
+    }
+}
+
+impl SliderRange {
+    /// Shows a slider named `name` for `value`, and the fields to change its range,
+    /// returning whether the slider was moved.
+    fn show(&mut self, ui: &mut egui::Ui, name: &str, value: &mut f64) -> bool {
+        // Values typed in outside of the range shouldn't be clamped until the slider is used.
+        let range = self.min.min(*value)..=self.max.max(*value);
+        let slider = ui.add(
+            egui::Slider::new(value, range)
+                .step_by(self.step)
+                .text(name),
+        );
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::DragValue::new(&mut self.min)
+                    .prefix("min: ")
+                    .speed(self.step),
+            );
+            ui.add(
+                egui::DragValue::new(&mut self.max)
+                    .prefix("max: ")
+                    .speed(self.step),
+            );
+            ui.add(
+                egui::DragValue::new(&mut self.step)
+                    .prefix("step: ")
+                    .speed(0.01)
+                    .clamp_range(0.001..=f64::INFINITY),
+            );
+        });
+        self.max = self.max.max(self.min);
+        slider.changed()
+    }
+
+    /// `value` with only as many decimals as the step has, so text doesn't fill up with rounding errors.
+    fn format(&self, value: f64) -> String {
+        let decimals = (-self.step.log10()).ceil().max(0.) as usize;
+        format!("{value:.decimals$}")
+    }
+}
 
 impl FunctionInput {
-    fn func(&self) -> Result<Function, ParseError> {
-        Function::try_from(self.0.clone())
+    /// The input as parsed at the start of the frame, see [`GraphErBrain::update_definitions`].
+    fn parsed(&mut self) -> &mut ParsedInput {
+        self.cache
+            .as_mut()
+            .expect("Inputs are parsed at the start of every frame")
     }
 
-    fn points(&self, minimum_x: f64, maximum_x: f64) -> Result<PlotPoints, ParseError> {
-        self.func()?.plot_points(minimum_x, maximum_x)
+    /// Whether the text hasn't changed since it was parsed.
+    fn is_parsed(&self) -> bool {
+        self.cache
+            .as_ref()
+            .is_some_and(|cache| cache.text == self.text)
     }
 
-    fn err(&self) -> Option<ParseError> {
-        // Check on calculations instead of function to catch any additional errors that may occur during later parsing.
-        match self.func() {
-            Err(e) => Some(e),
-            Ok(f) => {
-                if let Err(e) = f.y_pos(&f.generate_naive_map()) {
-                    Some(e)
-                } else {
-                    None
-                }
-            }
+    fn definition(&mut self) -> Result<&Definition, &ParseError> {
+        self.parsed().definition.as_ref()
+    }
+
+    /// The function the input defines, if it is a valid function.
+    fn function(&mut self) -> Option<&Arc<Function>> {
+        match self.definition() {
+            Ok(Definition::Function(function)) => Some(function),
+            _ => None,
+        }
+    }
+
+    /// The curve to plot, or `None` if the input isn't a valid function or nothing is sampled yet.
+    ///
+    /// Asks `worker` to sample the function again, as the job `next_job`, if the viewport was zoomed
+    /// or panned past the margin sampled last time. Until it is done, the last finished curve is returned.
+    fn curve(
+        &mut self,
+        input: usize,
+        viewport: &Viewport,
+        options: &SampleOptions,
+        worker: &Worker,
+        next_job: &mut u64,
+    ) -> Option<&SampledCurve> {
+        let function = Arc::clone(self.function()?);
+        let binding = function.bind(&self.arguments).ok()?;
+        let up_to_date = |job: &Job| job.covers(&function, &binding, viewport, options);
+        if !self
+            .sampled
+            .as_ref()
+            .is_some_and(|sampled| up_to_date(&sampled.job))
+            && !self.pending.as_ref().is_some_and(up_to_date)
+        {
+            *next_job += 1;
+            let job = Job {
+                input,
+                id: *next_job,
+                function,
+                binding,
+                viewport: *viewport,
+                options: options.clone(),
+            };
+            self.pending = Some(job.clone());
+            worker.submit(job);
+        }
+        self.sampled.as_ref()?.curve.as_ref().ok()
+    }
+
+    /// Takes in a job the worker finished, keeping it if it is newer than the curve sampled so far.
+    fn receive(&mut self, finished: Finished) {
+        if self
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.id == finished.job.id)
+        {
+            self.pending = None;
+        }
+        if self
+            .sampled
+            .as_ref()
+            .is_none_or(|sampled| sampled.job.id < finished.job.id)
+        {
+            self.sampled = Some(finished);
+        }
+    }
+
+    fn err(&mut self) -> Option<ParseError> {
+        self.definition().err().cloned()
+    }
+
+    /// The error calculating the input fails with, like a function calling itself or a calculation dividing by zero.
+    fn eval_err(&mut self) -> Option<EvalError> {
+        match self.definition() {
+            Ok(Definition::Function(function)) => function.program().err(),
+            Ok(Definition::Calculation(calculation)) => calculation.value.clone().err(),
+            _ => None,
+        }
+    }
+
+    /// The result of the input, if it is a calculation.
+    fn result(&mut self) -> Option<f64> {
+        match self.definition() {
+            Ok(Definition::Calculation(calculation)) => calculation.value.clone().ok(),
+            _ => None,
         }
     }
 
-    fn name(&self) -> Result<String, ParseError> {
-        Ok(self.func()?.name)
+    /// The body of the function with its derivatives worked out, if it has any.
+    fn expanded(&mut self) -> Option<String> {
+        Some(self.function()?.expanded()?.canonical().to_string())
     }
+
+    fn warning(&mut self) -> Option<ParseError> {
+        self.function()?.warning()
+    }
+}
+
+/// A distinct color for the function at `index`, shared by all the pieces of its curve.
+fn function_color(index: usize) -> Color32 {
+    // Stepping the hue by the golden ratio keeps neighbouring functions far apart in color.
+    let hue = (index as f32 * 0.618_034).fract();
+    Hsva::new(hue, 0.85, 0.5, 1.).into()
 }
+
+/// Lays out `text` like a normal text edit, but with `span` underlined in red to point out an error.
+fn underlined_layout_job(ui: &egui::Ui, text: &str, span: Option<Span>) -> LayoutJob {
+    let normal = TextFormat::simple(
+        egui::TextStyle::Body.resolve(ui.style()),
+        ui.visuals().text_color(),
+    );
+    let underlined = TextFormat {
+        underline: Stroke::new(1.5, Color32::RED),
+        ..normal.clone()
+    };
+
+    let span = span
+        .map(|span| {
+            // Errors at the end of the input have an empty span, so underline the character before it instead.
+            if span.is_empty() {
+                let start = text
+                    .get(..span.start)
+                    .and_then(|before| before.char_indices().last())
+                    .map_or(span.start, |(i, _)| i);
+                start..span.end
+            } else {
+                span
+            }
+        })
+        // The text may have been edited since the error was found.
+        .filter(|span| text.get(span.clone()).is_some());
+
+    let mut job = LayoutJob::default();
+    match span {
+        Some(span) => {
+            job.append(&text[..span.start], 0., normal.clone());
+            job.append(&text[span.clone()], 0., underlined);
+            job.append(&text[span.end..], 0., normal);
+        }
+        None => job.append(text, 0., normal),
+    }
+    job
+}
+
 #[derive(Default)]
 enum Zoom {
     Increase,
@@ -48,11 +273,49 @@ enum Zoom {
 }
 
 impl GraphErBrain {
-    pub fn new() -> Self {
+    pub fn new(cc: &eframe::CreationContext) -> Self {
         Self {
-            ..Default::default()
+            zoom: Zoom::default(),
+            function_thing: Vec::new(),
+            worker: Worker::new(cc.egui_ctx.clone()),
+            last_job: 0,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Parses every input again if the text of any of them changed,
+    /// so that changes reach every function calling the changed ones.
+    fn update_definitions(&mut self) {
+        if self.function_thing.iter().all(FunctionInput::is_parsed) {
+            return;
+        }
+        let texts = self
+            .function_thing
+            .iter()
+            .map(|input| input.text.as_str())
+            .collect::<Vec<_>>();
+        let parsed = Definition::parse_all(&texts, &self.limits);
+        for (input, definition) in self.function_thing.iter_mut().zip(parsed) {
+            match &mut input.cache {
+                // Keep the same function when it didn't change, so its curve isn't sampled again.
+                Some(cache) if cache.definition == definition => cache.text.clone_from(&input.text),
+                cache => {
+                    let legend = match &definition {
+                        Ok(Definition::Function(function)) => {
+                            Some(function.internal_representation())
+                        }
+                        _ => None,
+                    };
+                    *cache = Some(ParsedInput {
+                        text: input.text.clone(),
+                        definition,
+                        legend,
+                    })
+                }
+            }
         }
     }
+
     #[cfg(not(target_arch = "wasm32"))]
     pub fn start() -> eframe::Result<()> {
         env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`).
@@ -72,7 +335,7 @@ impl GraphErBrain {
         eframe::run_native(
             "Graph-ers",
             options,
-            Box::new(|_cc| Box::new(GraphErBrain::new())),
+            Box::new(|cc| Box::new(GraphErBrain::new(cc))),
         )
     }
 
@@ -87,7 +350,7 @@ impl GraphErBrain {
                 .start(
                     "the_canvas_id", // hardcode it
                     web_options,
-                    Box::new(|_cc| Box::new(GraphErBrain::new())),
+                    Box::new(|cc| Box::new(GraphErBrain::new(cc))),
                 )
                 .await
                 .expect("failed to start eframe");
@@ -97,6 +360,13 @@ impl GraphErBrain {
 
 impl eframe::App for GraphErBrain {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.update_definitions();
+        for finished in self.worker.finished() {
+            if let Some(input) = self.function_thing.get_mut(finished.job.input) {
+                input.receive(finished);
+            }
+        }
+
         // Persistence for the plot
         let mut plot_rect = None;
 
@@ -116,8 +386,31 @@ impl eframe::App for GraphErBrain {
                         if i != 0 {
                             ui.separator();
                         }
-                        ui.text_edit_singleline(&mut func_input.0);
-                        if let Some(error) = &func_input.err() {
+                        let error = func_input.err();
+                        let error_span = error.as_ref().and_then(ParseError::span);
+                        let mut layouter = |ui: &egui::Ui, text: &str, wrap_width: f32| {
+                            let mut job = underlined_layout_job(ui, text, error_span.clone());
+                            job.wrap.max_width = wrap_width;
+                            ui.fonts(|f| f.layout_job(job))
+                        };
+                        let result = func_input
+                            .result()
+                            .map(|result| result.to_string())
+                            .or_else(|| func_input.expanded());
+                        // Right to left, so the spinner doesn't push the input around.
+                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                            if let Some(result) = result {
+                                ui.label(format!("= {result}"));
+                            }
+                            ui.add(
+                                egui::TextEdit::singleline(&mut func_input.text)
+                                    .layouter(&mut layouter),
+                            );
+                            if func_input.pending.is_some() {
+                                ui.spinner();
+                            }
+                        });
+                        if let Some(error) = &error {
                             match error {
                                 ParseError::NoFunctionDefined => (),
                                 _ => {
@@ -125,6 +418,35 @@ impl eframe::App for GraphErBrain {
                                 }
                             }
                         }
+                        // Only a number can be replaced by the slider's value without losing anything,
+                        // so parameters written as an expression, like `r=sqrt(2)/2`, don't get one.
+                        let is_literal = Parameter::is_literal(&func_input.text);
+                        if let (true, Ok(Definition::Parameter(parameter))) =
+                            (is_literal, func_input.definition())
+                        {
+                            let (name, mut value) = (parameter.name.clone(), parameter.value);
+                            if func_input.slider.show(ui, &name, &mut value) {
+                                func_input.text =
+                                    format!("{name}={}", func_input.slider.format(value));
+                            }
+                        }
+                        // The first variable is plotted, and every other one gets a slider.
+                        let arguments = func_input
+                            .function()
+                            .map(|function| function.arguments().to_vec())
+                            .unwrap_or_default();
+                        for name in arguments {
+                            let value = func_input.arguments.entry(name.clone()).or_insert(1.);
+                            let slider =
+                                func_input.argument_sliders.entry(name.clone()).or_default();
+                            slider.show(ui, &name, value);
+                        }
+                        if let Some(error) = func_input.eval_err() {
+                            ui.label(RichText::new(error.to_string()).color(Color32::RED));
+                        }
+                        if let Some(warning) = func_input.warning() {
+                            ui.label(RichText::new(warning.to_string()).color(Color32::YELLOW));
+                        }
                     }
                 },
             );
@@ -149,42 +471,75 @@ impl eframe::App for GraphErBrain {
                 }
 
                 // Don't automatically zoom based on the graphs because
-                // otherwise it will go crazy because of the bounds
-                plot_ui.set_auto_bounds(Vec2b::new(false, true));
+                // otherwise it will go crazy because of the bounds.
+                // Turned off on both axes here, since skipping the zoom below leaves nothing else to do it.
+                plot_ui.set_auto_bounds(Vec2b::FALSE);
 
                 // Minimum and maximum bounds used for knowing which range is actually needed to calculate
                 let minimum_bound = plot_ui.plot_bounds().min();
                 let maximum_bound = plot_ui.plot_bounds().max();
 
+                // Zooming by a factor of 1 is skipped, since rounding could still nudge the bounds
+                // and make every function sample its points again.
                 let zoom_factor = match self.zoom {
-                    Zoom::Increase => Vec2::new(2., 2.),
-                    Zoom::Decrease => Vec2::new(0.5, 0.5),
-                    Zoom::Same => Vec2::new(1., 1.),
-                    Zoom::InitialState => Vec2::new(0.25, 0.25),
+                    Zoom::Increase => Some(Vec2::new(2., 2.)),
+                    Zoom::Decrease => Some(Vec2::new(0.5, 0.5)),
+                    Zoom::Same => None,
+                    Zoom::InitialState => Some(Vec2::new(0.25, 0.25)),
                 };
 
-                plot_ui.zoom_bounds(
-                    zoom_factor,
-                    PlotPoint::new(
-                        (minimum_bound[0] + maximum_bound[0]) / 2.,
-                        (minimum_bound[1] + maximum_bound[1]) / 2.,
-                    ),
-                );
+                if let Some(zoom_factor) = zoom_factor {
+                    plot_ui.zoom_bounds(
+                        zoom_factor,
+                        PlotPoint::new(
+                            (minimum_bound[0] + maximum_bound[0]) / 2.,
+                            (minimum_bound[1] + maximum_bound[1]) / 2.,
+                        ),
+                    );
+                }
                 self.zoom = Zoom::Same;
 
-                for func in &mut self.function_thing {
+                let plot_size = plot_ui.transform().frame().size();
+                let viewport = Viewport::new(plot_ui.plot_bounds(), [plot_size.x, plot_size.y]);
+                let sample_options = SampleOptions {
+                    detect_asymptotes: true,
+                    ..Default::default()
+                };
+                for (i, func) in self.function_thing.iter_mut().enumerate() {
                     // Ignore errors since that's handled elsewhere
-                    if let Ok(points) = func.points(minimum_bound[0], maximum_bound[1]) {
-                        plot_ui
-                            .line(Line::new(points).name(
-                                func.name().expect("Func already valid since points was ok"),
-                            ));
+                    let Some(name) = func.parsed().legend.clone() else {
+                        continue;
+                    };
+                    if let Some(curve) = func.curve(
+                        i,
+                        &viewport,
+                        &sample_options,
+                        &self.worker,
+                        &mut self.last_job,
+                    ) {
+                        let color = function_color(i);
+                        // Every piece gets the same name, so they share one entry in the legend.
+                        for segment in &curve.segments {
+                            plot_ui.line(
+                                Line::new(PlotPoints::from(segment.clone()))
+                                    .name(&name)
+                                    .color(color),
+                            );
+                        }
+                        for &x in &curve.asymptotes {
+                            plot_ui.vline(
+                                VLine::new(x)
+                                    .name(&name)
+                                    .color(color.gamma_multiply(0.5))
+                                    .style(LineStyle::dashed_loose()),
+                            );
+                        }
                     }
                 }
                 if self
                     .function_thing
-                    .iter()
-                    .all(|f| !f.0.is_empty() && f.err().is_none())
+                    .iter_mut()
+                    .all(|f| !f.text.is_empty() && f.err().is_none())
                 {
                     // All have text and none have errors (because it indicates usage),
                     // so add an empty text box
@@ -0,0 +1,271 @@
+use crate::parse::{Binding, EvalError, Function, SampleOptions, SampledCurve, Viewport};
+use std::sync::Arc;
+
+/// A request to sample the function of one input for a viewport.
+#[derive(Clone)]
+pub(super) struct Job {
+    /// Index of the input the function belongs to.
+    pub input: usize,
+    /// Increases with every job, so newer results can be told apart from older ones.
+    pub id: u64,
+    pub function: Arc<Function>,
+    /// The values of the function's variables other than the plotted one.
+    pub binding: Binding,
+    pub viewport: Viewport,
+    pub options: SampleOptions,
+}
+
+/// The result of a [`Job`].
+pub(super) struct Finished {
+    pub job: Job,
+    pub curve: Result<SampledCurve, EvalError>,
+}
+
+impl Job {
+    /// Whether the result of this job can be drawn for `function` in `viewport`,
+    /// meaning it was sampled from the same parsed function and arguments at the same zoom, and covers everything visible.
+    pub fn covers(
+        &self,
+        function: &Arc<Function>,
+        binding: &Binding,
+        viewport: &Viewport,
+        options: &SampleOptions,
+    ) -> bool {
+        let (sampled_min, sampled_max) = self.viewport.sample_x();
+        let (visible_min, visible_max) = viewport.visible_x();
+        Arc::ptr_eq(&self.function, function)
+            && self.binding == *binding
+            && same_sampling(
+                &self.viewport.sample_options(&self.options),
+                &viewport.sample_options(options),
+            )
+            && sampled_min <= visible_min
+            && visible_max <= sampled_max
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn run(self) -> Finished {
+        let curve = self
+            .function
+            .plot_points(&self.viewport, &self.options, &self.binding);
+        Finished { job: self, curve }
+    }
+}
+
+/// Whether sampling with `a` and `b` gives practically the same curve.
+///
+/// The vertical scale only has to be within the same power of two, since the y-bounds move
+/// with every vertical drag, and resampling for each small change would never let the plot settle.
+fn same_sampling(a: &SampleOptions, b: &SampleOptions) -> bool {
+    let bucket = |options: &SampleOptions| options.scale[1].abs().log2().round();
+    let without_y = |options: &SampleOptions| SampleOptions {
+        scale: [options.scale[0], 0.],
+        ..options.clone()
+    };
+    without_y(a) == without_y(b) && bucket(a) == bucket(b)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(super) use native::Worker;
+#[cfg(target_arch = "wasm32")]
+pub(super) use web::Worker;
+
+/// Samples functions on a pool of threads, so the UI keeps drawing while they are calculated.
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use super::{Finished, Job};
+    use eframe::egui;
+    use std::collections::VecDeque;
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread;
+
+    #[derive(Default)]
+    struct Queue {
+        jobs: VecDeque<Job>,
+        /// Set when the worker is dropped, to let the threads finish.
+        closed: bool,
+    }
+
+    pub(in crate::app) struct Worker {
+        queue: Arc<(Mutex<Queue>, Condvar)>,
+        finished: Receiver<Finished>,
+    }
+
+    impl Worker {
+        /// Starts a thread for every core, which repaint `ctx` whenever they finish a job.
+        pub fn new(ctx: egui::Context) -> Self {
+            let queue = Arc::new((Mutex::new(Queue::default()), Condvar::new()));
+            let (sender, finished) = mpsc::channel();
+            let threads = thread::available_parallelism().map_or(1, |n| n.get());
+            for _ in 0..threads {
+                let queue = Arc::clone(&queue);
+                let sender = sender.clone();
+                let ctx = ctx.clone();
+                thread::spawn(move || Self::work(&queue, &sender, &ctx));
+            }
+            Self { queue, finished }
+        }
+
+        fn work(queue: &(Mutex<Queue>, Condvar), sender: &Sender<Finished>, ctx: &egui::Context) {
+            let (lock, ready) = queue;
+            loop {
+                let job = {
+                    let mut queue = lock.lock().expect("Worker threads don't panic");
+                    loop {
+                        if queue.closed {
+                            return;
+                        }
+                        match queue.jobs.pop_front() {
+                            Some(job) => break job,
+                            None => queue = ready.wait(queue).expect("Worker threads don't panic"),
+                        }
+                    }
+                };
+                if sender.send(job.run()).is_err() {
+                    return;
+                }
+                ctx.request_repaint();
+            }
+        }
+
+        /// Queues `job`, replacing any job for the same input that hasn't started yet.
+        pub fn submit(&self, job: Job) {
+            let (lock, ready) = &*self.queue;
+            let mut queue = lock.lock().expect("Worker threads don't panic");
+            queue.jobs.retain(|queued| queued.input != job.input);
+            queue.jobs.push_back(job);
+            ready.notify_one();
+        }
+
+        /// The jobs finished since the last call.
+        pub fn finished(&self) -> Vec<Finished> {
+            self.finished.try_iter().collect()
+        }
+    }
+
+    impl Drop for Worker {
+        fn drop(&mut self) {
+            let (lock, ready) = &*self.queue;
+            if let Ok(mut queue) = lock.lock() {
+                queue.closed = true;
+            }
+            ready.notify_all();
+        }
+    }
+}
+
+/// Samples functions a slice of points per frame, since the web has no threads to spare,
+/// so the UI still gets to draw while a heavy function is sampled.
+#[cfg(target_arch = "wasm32")]
+mod web {
+    use super::{Finished, Job};
+    use crate::parse::AdaptiveSampler;
+    use eframe::egui;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    /// How many points are calculated per frame.
+    const POINTS_PER_FRAME: usize = 512;
+
+    pub(in crate::app) struct Worker {
+        jobs: RefCell<VecDeque<Job>>,
+        /// The job being sampled, with how far it got.
+        running: RefCell<Option<(Job, AdaptiveSampler)>>,
+        ctx: egui::Context,
+    }
+
+    impl Worker {
+        pub fn new(ctx: egui::Context) -> Self {
+            Self {
+                jobs: RefCell::default(),
+                running: RefCell::default(),
+                ctx,
+            }
+        }
+
+        /// Queues `job`, replacing any job for the same input that hasn't finished yet.
+        pub fn submit(&self, job: Job) {
+            let mut running = self.running.borrow_mut();
+            if running
+                .as_ref()
+                .is_some_and(|(running, _)| running.input == job.input)
+            {
+                *running = None;
+            }
+            let mut jobs = self.jobs.borrow_mut();
+            jobs.retain(|queued| queued.input != job.input);
+            jobs.push_back(job);
+            self.ctx.request_repaint();
+        }
+
+        /// Samples the next slice of the current job, leaving the rest for the following frames.
+        pub fn finished(&self) -> Vec<Finished> {
+            let mut running = self.running.borrow_mut();
+            let mut jobs = self.jobs.borrow_mut();
+            let (job, mut sampler) = match running.take() {
+                Some(running) => running,
+                None => match jobs.pop_front() {
+                    Some(job) => {
+                        let sampler = job.function.start_plot(&job.viewport, &job.options);
+                        (job, sampler)
+                    }
+                    None => return Vec::new(),
+                },
+            };
+            let finished =
+                match job
+                    .function
+                    .continue_plot(&mut sampler, &job.binding, POINTS_PER_FRAME)
+                {
+                    Some(curve) => vec![Finished { job, curve }],
+                    None => {
+                        *running = Some((job, sampler));
+                        Vec::new()
+                    }
+                };
+            if running.is_some() || !jobs.is_empty() {
+                self.ctx.request_repaint();
+            }
+            finished
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Job;
+    use crate::parse::{Binding, Function, SampleOptions, Viewport};
+    use egui_plot::PlotBounds;
+    use std::sync::Arc;
+
+    #[test]
+    fn covered_viewports() {
+        let function = Arc::new(Function::try_from("f(x)=x^2").unwrap());
+        let viewport = |min: [f64; 2], max: [f64; 2]| {
+            Viewport::new(PlotBounds::from_min_max(min, max), [800., 600.])
+        };
+        let job = Job {
+            input: 0,
+            id: 0,
+            function: Arc::clone(&function),
+            binding: Binding::default(),
+            viewport: viewport([-10., -10.], [10., 10.]),
+            options: SampleOptions::default(),
+        };
+        let covers = |viewport: Viewport| {
+            job.covers(
+                &function,
+                &Binding::default(),
+                &viewport,
+                &SampleOptions::default(),
+            )
+        };
+        assert!(covers(job.viewport));
+        // Small vertical changes, like the curve being dragged up, don't need new points.
+        assert!(covers(viewport([-10., -8.], [10., 13.])));
+        assert!(!covers(viewport([-10., -100.], [10., 100.])));
+        assert!(!covers(viewport([-5., -10.], [5., 10.])));
+        assert!(!covers(viewport([-20., -10.], [0., 10.])));
+    }
+}
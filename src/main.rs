@@ -1,6 +1,3 @@
-#![feature(ascii_char)]
-#![feature(slice_take)]
-#![feature(let_chains)]
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
 
 pub mod app;
@@ -10,6 +7,7 @@ pub mod parse;
 pub use app::GraphErBrain;
 
 #[cfg(not(target_arch = "wasm32"))]
+#[allow(dead_code)] // Only used by the binary target, not the library.
 fn main() -> Result<(), eframe::Error> {
     GraphErBrain::start()
 }
@@ -0,0 +1,242 @@
+use crate::parse::math_functions::EvalError;
+use prse::{try_parse, Parse};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// A function that is always available in expressions, like `sin(x)` or `max(a,b)`.
+#[derive(Parse, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    #[prse = "sin"]
+    Sin,
+    #[prse = "cos"]
+    Cos,
+    #[prse = "tan"]
+    Tan,
+    #[prse = "asin"]
+    Asin,
+    #[prse = "acos"]
+    Acos,
+    #[prse = "atan"]
+    Atan,
+    #[prse = "sinh"]
+    Sinh,
+    #[prse = "cosh"]
+    Cosh,
+    #[prse = "tanh"]
+    Tanh,
+    #[prse = "exp"]
+    Exp,
+    #[prse = "ln"]
+    Ln,
+    /// Base 10 logarithm, or `log(x,b)` for base `b`.
+    #[prse = "log"]
+    Log,
+    #[prse = "sqrt"]
+    Sqrt,
+    #[prse = "abs"]
+    Abs,
+    #[prse = "floor"]
+    Floor,
+    #[prse = "ceil"]
+    Ceil,
+    #[prse = "round"]
+    Round,
+    #[prse = "min"]
+    Min,
+    #[prse = "max"]
+    Max,
+}
+
+impl Builtin {
+    pub const ALL: [Builtin; 19] = [
+        Self::Sin,
+        Self::Cos,
+        Self::Tan,
+        Self::Asin,
+        Self::Acos,
+        Self::Atan,
+        Self::Sinh,
+        Self::Cosh,
+        Self::Tanh,
+        Self::Exp,
+        Self::Ln,
+        Self::Log,
+        Self::Sqrt,
+        Self::Abs,
+        Self::Floor,
+        Self::Ceil,
+        Self::Round,
+        Self::Min,
+        Self::Max,
+    ];
+
+    /// Looks up a built-in function by the name it is called with.
+    pub fn from_name(name: &str) -> Option<Self> {
+        try_parse!(name, "{}").ok()
+    }
+
+    /// The name the function is called with in expressions.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sin => "sin",
+            Self::Cos => "cos",
+            Self::Tan => "tan",
+            Self::Asin => "asin",
+            Self::Acos => "acos",
+            Self::Atan => "atan",
+            Self::Sinh => "sinh",
+            Self::Cosh => "cosh",
+            Self::Tanh => "tanh",
+            Self::Exp => "exp",
+            Self::Ln => "ln",
+            Self::Log => "log",
+            Self::Sqrt => "sqrt",
+            Self::Abs => "abs",
+            Self::Floor => "floor",
+            Self::Ceil => "ceil",
+            Self::Round => "round",
+            Self::Min => "min",
+            Self::Max => "max",
+        }
+    }
+
+    /// The allowed number of arguments.
+    pub fn arity(self) -> RangeInclusive<usize> {
+        match self {
+            Self::Log => 1..=2,
+            Self::Min | Self::Max => 1..=usize::MAX,
+            _ => 1..=1,
+        }
+    }
+
+    /// Calculates the function for already calculated arguments,
+    /// failing if the function is undefined for them.
+    ///
+    /// The number of arguments is expected to be within [`Builtin::arity`].
+    pub fn checked_apply(self, args: &[f64]) -> Result<f64, EvalError> {
+        let x = args[0];
+        match self {
+            // Logarithms go to infinity rather than NaN at zero, which is not an overflow.
+            Self::Ln | Self::Log if x == 0. => Err(EvalError::DomainError(self.to_string(), x)),
+            Self::Log if args.get(1) == Some(&1.) => {
+                Err(EvalError::DomainError(self.to_string(), x))
+            }
+            _ => EvalError::check(self, self.apply(args), args),
+        }
+    }
+
+    /// Calculates the function for already calculated arguments.
+    ///
+    /// The number of arguments is expected to be within [`Builtin::arity`].
+    pub fn apply(self, args: &[f64]) -> f64 {
+        let x = args[0];
+        match self {
+            Self::Sin => x.sin(),
+            Self::Cos => x.cos(),
+            Self::Tan => x.tan(),
+            Self::Asin => x.asin(),
+            Self::Acos => x.acos(),
+            Self::Atan => x.atan(),
+            Self::Sinh => x.sinh(),
+            Self::Cosh => x.cosh(),
+            Self::Tanh => x.tanh(),
+            Self::Exp => x.exp(),
+            Self::Ln => x.ln(),
+            Self::Log => match args.get(1) {
+                Some(base) => x.log(*base),
+                None => x.log10(),
+            },
+            Self::Sqrt => x.sqrt(),
+            Self::Abs => x.abs(),
+            Self::Floor => x.floor(),
+            Self::Ceil => x.ceil(),
+            Self::Round => x.round(),
+            Self::Min => args.iter().copied().fold(f64::INFINITY, f64::min),
+            Self::Max => args.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+impl fmt::Display for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A named mathematical constant, like `pi`.
+#[derive(Parse, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Constant {
+    #[prse = "pi"]
+    Pi,
+    #[prse = "e"]
+    E,
+    #[prse = "tau"]
+    Tau,
+    /// The golden ratio.
+    #[prse = "phi"]
+    Phi,
+}
+
+impl Constant {
+    pub const ALL: [Constant; 4] = [Self::Pi, Self::E, Self::Tau, Self::Phi];
+
+    /// Looks up a constant by the name it is written with.
+    pub fn from_name(name: &str) -> Option<Self> {
+        try_parse!(name, "{}").ok()
+    }
+
+    /// The name the constant is written with in expressions.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Pi => "pi",
+            Self::E => "e",
+            Self::Tau => "tau",
+            Self::Phi => "phi",
+        }
+    }
+
+    pub fn value(self) -> f64 {
+        match self {
+            Self::Pi => std::f64::consts::PI,
+            Self::E => std::f64::consts::E,
+            Self::Tau => std::f64::consts::TAU,
+            // (1 + sqrt(5)) / 2
+            Self::Phi => 1.618_033_988_749_895,
+        }
+    }
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::{Builtin, Constant, Function};
+    use std::collections::HashMap;
+
+    #[test]
+    fn names_round_trip() {
+        for builtin in Builtin::ALL {
+            assert_eq!(Builtin::from_name(builtin.name()), Some(builtin));
+        }
+        for constant in Constant::ALL {
+            assert_eq!(Constant::from_name(constant.name()), Some(constant));
+        }
+        assert_eq!(Builtin::from_name("test"), None);
+    }
+
+    #[test]
+    fn call_with_expressions() {
+        let func = Function::try_from("f(x)=sin(2x)+sqrt(x)").unwrap();
+        let y = func.y_pos(&HashMap::from([("x".to_string(), 4.)])).unwrap();
+        assert!((y - (8f64.sin() + 2.)).abs() < 1e-12);
+
+        let func =
+            Function::try_from("f(x)=max(x,-x,1)+log(100)+log(8,2)+abs(floor(-x/2))").unwrap();
+        let y = func.y_pos(&HashMap::from([("x".to_string(), 3.)])).unwrap();
+        assert!((y - (3. + 2. + 3. + 2.)).abs() < 1e-12);
+    }
+}
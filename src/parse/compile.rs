@@ -0,0 +1,804 @@
+use crate::parse::builtins::Builtin;
+use crate::parse::derivative::{is_derivative, DIFF};
+use crate::parse::expr::{BinaryOp, Expr, UnaryOp};
+use crate::parse::limits::Limits;
+use crate::parse::math_functions::{EvalError, Function};
+use crate::parse::scope::Scope;
+
+/// How many values [`Program::evaluate_batch`] calculates at a time, few enough to stay in the cache.
+const BATCH_CHUNK: usize = 256;
+
+/// The values of a variable in [`Program::evaluate_columns`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Column<'a> {
+    /// A different value for every set of values.
+    Values(&'a [f64]),
+    /// The same value for all of them.
+    Constant(f64),
+}
+
+/// A single step of a [`Program`], working on a stack of values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Instruction {
+    /// Pushes a number.
+    Push(f64),
+    /// Pushes the value of the variable in the given slot.
+    Load(usize),
+    /// Replaces the top value with its negation.
+    Negate,
+    /// Replaces the two top values with the result of the operator, the top one being the right operand.
+    Binary(BinaryOp),
+    /// Replaces the given number of top values with the result of calling the function on them.
+    Call(Builtin, usize),
+    /// Pushes a copy of the value at the given index from the bottom of the stack,
+    /// which is how an inlined function reads its arguments.
+    Copy(usize),
+    /// Removes the given number of values below the top one,
+    /// the arguments of an inlined function once its result is calculated.
+    Return(usize),
+}
+
+/// The function an expression being compiled belongs to, so its variables and calls can be resolved.
+struct Frame<'a> {
+    /// The name of the function, if the expression is the body of one.
+    name: Option<&'a str>,
+    /// For an inlined function, its declared variables and the stack index of its first argument.
+    arguments: Option<(&'a [String], usize)>,
+    /// The frame of the expression the function is called from.
+    caller: Option<&'a Frame<'a>>,
+}
+
+impl Frame<'_> {
+    /// Whether the function called `name` is being compiled in this frame or one of its callers.
+    fn is_compiling(&self, name: &str) -> bool {
+        self.name == Some(name) || self.caller.is_some_and(|caller| caller.is_compiling(name))
+    }
+}
+
+/// An expression compiled to a flat list of stack instructions, for calculating it many times quickly.
+///
+/// Variables are looked up by their slot, their index in [`Program::slots`], instead of by name.
+/// Calculating a program gives the same result as [`Expr::evaluate`] with the same variables.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+    slots: Vec<String>,
+    /// The most values on the stack at once, so it can be allocated up front.
+    max_stack: usize,
+}
+
+impl Program {
+    /// Compiles `expr`, with `slots` being the names of the variables it is calculated with.
+    ///
+    /// Fails with the error the expression would always fail to evaluate with,
+    /// like an unknown variable or function, or taking more steps than `limits` allow.
+    /// Every instruction is one step, and each is run exactly once per calculation.
+    pub fn compile(expr: &Expr, slots: &[String], limits: &Limits) -> Result<Self, EvalError> {
+        Self::compile_function(None, expr, slots, &Scope::new(), limits)
+    }
+
+    /// Compiles the body `expr` of the function called `name`, inlining calls to the functions in `scope`.
+    ///
+    /// Calling `name` from the body, directly or through other functions, fails with [`EvalError::RecursiveCall`],
+    /// rather than using an older definition of it from `scope`.
+    pub(crate) fn compile_function(
+        name: Option<&str>,
+        expr: &Expr,
+        slots: &[String],
+        scope: &Scope,
+        limits: &Limits,
+    ) -> Result<Self, EvalError> {
+        let mut program = Self {
+            instructions: Vec::new(),
+            slots: slots.to_vec(),
+            max_stack: 0,
+        };
+        let frame = Frame {
+            name,
+            arguments: None,
+            caller: None,
+        };
+        program.emit(expr, 0, 0, &frame, scope, limits)?;
+        if program.instructions.len() > limits.max_steps {
+            return Err(EvalError::StepLimit(limits.max_steps));
+        }
+        Ok(program)
+    }
+
+    /// The names of the variables, in the order their values are given to [`Program::evaluate`].
+    pub fn slots(&self) -> &[String] {
+        &self.slots
+    }
+
+    /// Calculates the program with the variable in every slot set to the value at the same index.
+    ///
+    /// `values` is expected to have a value for every slot.
+    pub fn evaluate(&self, values: &[f64]) -> Result<f64, EvalError> {
+        self.evaluate_with_stack(values, &mut Vec::with_capacity(self.max_stack))
+    }
+
+    /// Same as [`Program::evaluate`], but reusing `stack` to avoid allocating when called many times.
+    pub fn evaluate_with_stack(
+        &self,
+        values: &[f64],
+        stack: &mut Vec<f64>,
+    ) -> Result<f64, EvalError> {
+        // Checking every step for errors is slow, but every error gives a result that isn't finite,
+        // so they only have to be checked for, and found, when that happens.
+        match self.evaluate_unchecked(values, stack) {
+            Some(result) => Ok(result),
+            None => self.evaluate_checked(values, stack),
+        }
+    }
+
+    /// Calculates the program without checking for errors,
+    /// or `None` if any step doesn't give a finite result, so it may have failed.
+    fn evaluate_unchecked(&self, values: &[f64], stack: &mut Vec<f64>) -> Option<f64> {
+        if stack.len() < self.max_stack {
+            stack.resize(self.max_stack, 0.);
+        }
+        let mut height = 0;
+        let mut finite = true;
+        for instruction in &self.instructions {
+            match *instruction {
+                Instruction::Push(num) => {
+                    stack[height] = num;
+                    height += 1;
+                }
+                Instruction::Load(slot) => {
+                    stack[height] = values[slot];
+                    height += 1;
+                }
+                Instruction::Negate => stack[height - 1] = -stack[height - 1],
+                Instruction::Binary(op) => {
+                    height -= 1;
+                    let result = op.apply(stack[height - 1], stack[height]);
+                    finite &= result.is_finite();
+                    stack[height - 1] = result;
+                }
+                Instruction::Call(builtin, arg_count) => {
+                    height -= arg_count;
+                    let result = builtin.apply(&stack[height..height + arg_count]);
+                    finite &= result.is_finite();
+                    stack[height] = result;
+                    height += 1;
+                }
+                Instruction::Copy(index) => {
+                    stack[height] = stack[index];
+                    height += 1;
+                }
+                Instruction::Return(arg_count) => {
+                    height -= arg_count;
+                    stack[height - 1] = stack[height + arg_count - 1];
+                }
+            }
+        }
+        finite.then_some(stack[0])
+    }
+
+    fn evaluate_checked(&self, values: &[f64], stack: &mut Vec<f64>) -> Result<f64, EvalError> {
+        stack.clear();
+        for instruction in &self.instructions {
+            match *instruction {
+                Instruction::Push(num) => stack.push(num),
+                Instruction::Load(slot) => stack.push(values[slot]),
+                Instruction::Negate => {
+                    let top = stack.last_mut().expect("Compiled with an operand");
+                    *top = -*top;
+                }
+                Instruction::Binary(op) => {
+                    let rhs = stack.pop().expect("Compiled with two operands");
+                    let lhs = stack.last_mut().expect("Compiled with two operands");
+                    *lhs = op.checked_apply(*lhs, rhs)?;
+                }
+                Instruction::Call(builtin, arg_count) => {
+                    let args_start = stack.len() - arg_count;
+                    let result = builtin.checked_apply(&stack[args_start..])?;
+                    stack.truncate(args_start);
+                    stack.push(result);
+                }
+                Instruction::Copy(index) => stack.push(stack[index]),
+                Instruction::Return(arg_count) => {
+                    let result = stack.pop().expect("Compiled with a result");
+                    stack.truncate(stack.len() - arg_count);
+                    stack.push(result);
+                }
+            }
+        }
+        Ok(stack.pop().expect("Compiled with a result"))
+    }
+
+    /// Calculates the program for many values of its variables at once, writing the results to `output`.
+    ///
+    /// `columns` has the values of the variable in every slot, each as long as `output`.
+    /// Where [`Program::evaluate`] would fail for a set of values, the result is NaN instead.
+    ///
+    /// # Panics
+    ///
+    /// If there isn't a column for every slot, or a column is not as long as `output`.
+    pub fn evaluate_batch(&self, columns: &[&[f64]], output: &mut [f64]) {
+        let columns = columns
+            .iter()
+            .map(|column| Column::Values(column))
+            .collect::<Vec<_>>();
+        self.evaluate_columns(&columns, output);
+    }
+
+    /// Same as [`Program::evaluate_batch`], but with some variables the same for every set of values,
+    /// so they don't need a column as long as the output.
+    pub(crate) fn evaluate_columns(&self, columns: &[Column<'_>], output: &mut [f64]) {
+        assert_eq!(
+            columns.len(),
+            self.slots.len(),
+            "Expected a column for every slot"
+        );
+        assert!(
+            columns.iter().all(|column| match column {
+                Column::Values(values) => values.len() == output.len(),
+                Column::Constant(_) => true,
+            }),
+            "Expected every column to be as long as the output"
+        );
+
+        let mut stack = vec![[0.; BATCH_CHUNK]; self.max_stack];
+        let mut args = Vec::new();
+        for start in (0..output.len()).step_by(BATCH_CHUNK) {
+            let len = BATCH_CHUNK.min(output.len() - start);
+            let mut height = 0;
+            for instruction in &self.instructions {
+                match *instruction {
+                    Instruction::Push(num) => {
+                        stack[height][..len].fill(num);
+                        height += 1;
+                    }
+                    Instruction::Load(slot) => {
+                        match columns[slot] {
+                            Column::Values(values) => {
+                                stack[height][..len].copy_from_slice(&values[start..start + len])
+                            }
+                            Column::Constant(value) => stack[height][..len].fill(value),
+                        }
+                        height += 1;
+                    }
+                    Instruction::Negate => {
+                        for value in &mut stack[height - 1][..len] {
+                            *value = -*value;
+                        }
+                    }
+                    Instruction::Binary(op) => {
+                        height -= 1;
+                        let (below, above) = stack.split_at_mut(height);
+                        binary_columns(op, &mut below[height - 1][..len], &above[0][..len]);
+                    }
+                    Instruction::Call(builtin, arg_count) => {
+                        height -= arg_count;
+                        if arg_count == 1 {
+                            // Most calls take a single argument, which needs no gathering.
+                            for value in &mut stack[height][..len] {
+                                if !value.is_nan() {
+                                    // Only results that aren't finite can be errors, see `evaluate_with_stack`.
+                                    let result = builtin.apply(&[*value]);
+                                    *value = if result.is_finite() {
+                                        result
+                                    } else {
+                                        builtin.checked_apply(&[*value]).unwrap_or(f64::NAN)
+                                    };
+                                }
+                            }
+                        } else {
+                            for i in 0..len {
+                                args.clear();
+                                args.extend(
+                                    stack[height..height + arg_count].iter().map(|arg| arg[i]),
+                                );
+                                stack[height][i] = if args.iter().any(|arg| arg.is_nan()) {
+                                    f64::NAN
+                                } else {
+                                    builtin.checked_apply(&args).unwrap_or(f64::NAN)
+                                };
+                            }
+                        }
+                        height += 1;
+                    }
+                    Instruction::Copy(index) => {
+                        let (below, above) = stack.split_at_mut(height);
+                        above[0][..len].copy_from_slice(&below[index][..len]);
+                        height += 1;
+                    }
+                    Instruction::Return(arg_count) => {
+                        height -= arg_count;
+                        let (below, above) = stack.split_at_mut(height);
+                        below[height - 1][..len].copy_from_slice(&above[arg_count - 1][..len]);
+                    }
+                }
+            }
+            output[start..start + len].copy_from_slice(&stack[0][..len]);
+        }
+    }
+
+    /// Adds the instructions for `expr`, which starts out with `height` values already on the stack,
+    /// with its variables and calls resolved in `frame`.
+    fn emit(
+        &mut self,
+        expr: &Expr,
+        height: usize,
+        depth: usize,
+        frame: &Frame<'_>,
+        scope: &Scope,
+        limits: &Limits,
+    ) -> Result<(), EvalError> {
+        if depth > limits.max_evaluation_depth {
+            return Err(EvalError::RecursionLimit);
+        }
+        // Inlining can make a program grow quickly, so stop as soon as it is too large.
+        if self.instructions.len() > limits.max_steps {
+            return Err(EvalError::StepLimit(limits.max_steps));
+        }
+        let depth = depth + 1;
+        match expr {
+            Expr::Number(num) => self.instructions.push(Instruction::Push(*num)),
+            Expr::Constant(constant) => self.instructions.push(Instruction::Push(constant.value())),
+            Expr::Variable(var_name) => {
+                let instruction = match frame.arguments {
+                    Some((variables, first_argument)) => variables
+                        .iter()
+                        .position(|var| var == var_name)
+                        .map(|i| Instruction::Copy(first_argument + i)),
+                    None => self
+                        .slots
+                        .iter()
+                        .position(|slot| slot == var_name)
+                        .map(Instruction::Load),
+                };
+                let instruction = instruction
+                    .or_else(|| scope.parameter(var_name).map(Instruction::Push))
+                    .ok_or_else(|| EvalError::UnknownVariable(var_name.clone()))?;
+                self.instructions.push(instruction);
+            }
+            Expr::Unary(UnaryOp::Negate, operand) => {
+                self.emit(operand, height, depth, frame, scope, limits)?;
+                self.instructions.push(Instruction::Negate);
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                self.emit(lhs, height, depth, frame, scope, limits)?;
+                self.emit(rhs, height + 1, depth, frame, scope, limits)?;
+                self.instructions.push(Instruction::Binary(*op));
+            }
+            Expr::Call(name, args) => {
+                self.emit_call(name, args, height, depth, frame, scope, limits)?;
+            }
+        }
+        // Every expression leaves exactly one more value on the stack than it started with.
+        self.max_stack = self.max_stack.max(height + 1);
+        Ok(())
+    }
+
+    /// Adds the instructions for a call to the function `name` with `args`, like [`Program::emit`].
+    ///
+    /// Kept out of [`Program::emit`] so that it takes less stack space for every level of nesting.
+    #[allow(clippy::too_many_arguments)]
+    fn emit_call(
+        &mut self,
+        name: &str,
+        args: &[Expr],
+        height: usize,
+        depth: usize,
+        frame: &Frame<'_>,
+        scope: &Scope,
+        limits: &Limits,
+    ) -> Result<(), EvalError> {
+        let base_name = name.trim_end_matches('\'');
+        if frame.is_compiling(base_name) {
+            return Err(EvalError::RecursiveCall(base_name.to_string()));
+        }
+        if is_derivative(name) {
+            let derivative = expand(
+                &Expr::Call(name.to_string(), args.to_vec()),
+                frame,
+                scope,
+                limits,
+            )?;
+            return self.emit(&derivative, height, depth, frame, scope, limits);
+        }
+        for (i, arg) in args.iter().enumerate() {
+            self.emit(arg, height + i, depth, frame, scope, limits)?;
+        }
+        if let Some(function) = scope.get(name) {
+            self.inline(function, args.len(), height, depth, frame, scope, limits)?;
+        } else if scope.is_undefined(name) {
+            return Err(EvalError::DependencyError(name.to_string()));
+        } else {
+            let builtin = Builtin::from_name(name)
+                .filter(|builtin| builtin.arity().contains(&args.len()))
+                .ok_or_else(|| EvalError::UnknownFunction(name.to_string(), args.len()))?;
+            self.instructions
+                .push(Instruction::Call(builtin, args.len()));
+        }
+        Ok(())
+    }
+
+    /// Adds the instructions for the body of `function`, called from `caller`
+    /// with its `arg_count` arguments already on the stack from index `height`.
+    #[allow(clippy::too_many_arguments)]
+    fn inline(
+        &mut self,
+        function: &Function,
+        arg_count: usize,
+        height: usize,
+        depth: usize,
+        caller: &Frame<'_>,
+        scope: &Scope,
+        limits: &Limits,
+    ) -> Result<(), EvalError> {
+        if function.variables().len() != arg_count {
+            return Err(EvalError::UnknownFunction(function.name.clone(), arg_count));
+        }
+        let frame = Frame {
+            name: Some(&function.name),
+            arguments: Some((function.variables(), height)),
+            caller: Some(caller),
+        };
+        self.emit(
+            function.expr(),
+            height + arg_count,
+            depth,
+            &frame,
+            scope,
+            limits,
+        )?;
+        if arg_count > 0 {
+            self.instructions.push(Instruction::Return(arg_count));
+        }
+        Ok(())
+    }
+}
+
+/// `expr` with every derivative in it worked out, so it can be shown as an expression without them,
+/// for the function called `name` if it is the body of one, see [`Program::compile_function`].
+///
+/// Calls that aren't inside a derivative are kept as they are.
+pub(crate) fn expand_derivatives(
+    name: Option<&str>,
+    expr: &Expr,
+    scope: &Scope,
+    limits: &Limits,
+) -> Result<Expr, EvalError> {
+    fn expand_within(
+        expr: &Expr,
+        frame: &Frame<'_>,
+        scope: &Scope,
+        limits: &Limits,
+    ) -> Result<Expr, EvalError> {
+        Ok(match expr {
+            Expr::Call(name, _) if is_derivative(name) => expand(expr, frame, scope, limits)?,
+            Expr::Number(_) | Expr::Constant(_) | Expr::Variable(_) => expr.clone(),
+            Expr::Unary(op, operand) => {
+                Expr::unary(*op, expand_within(operand, frame, scope, limits)?)
+            }
+            Expr::Binary(op, lhs, rhs) => Expr::binary(
+                *op,
+                expand_within(lhs, frame, scope, limits)?,
+                expand_within(rhs, frame, scope, limits)?,
+            ),
+            Expr::Call(name, args) => Expr::Call(
+                name.clone(),
+                args.iter()
+                    .map(|arg| expand_within(arg, frame, scope, limits))
+                    .collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+    let frame = Frame {
+        name,
+        arguments: None,
+        caller: None,
+    };
+    expand_within(expr, &frame, scope, limits)
+}
+
+/// `expr`, resolved in `frame`, with every call to a function in `scope` replaced by the function's body
+/// and every derivative worked out, so that it only calls built-in functions and can be differentiated.
+///
+/// Fails like compiling would, and with [`EvalError::StepLimit`] if the expression grows larger than
+/// `limits` allow for the steps of a program.
+fn expand(
+    expr: &Expr,
+    frame: &Frame<'_>,
+    scope: &Scope,
+    limits: &Limits,
+) -> Result<Expr, EvalError> {
+    expand_counted(expr, frame, scope, limits).map(|(expanded, _)| expanded)
+}
+
+/// [`expand`], which also returns how many nodes the expanded expression has.
+///
+/// The count is added up from the children, so only the results of differentiating and
+/// inlining, which are new trees, have to be walked to count them.
+fn expand_counted(
+    expr: &Expr,
+    frame: &Frame<'_>,
+    scope: &Scope,
+    limits: &Limits,
+) -> Result<(Expr, usize), EvalError> {
+    let (expanded, nodes) = match expr {
+        Expr::Number(_) | Expr::Constant(_) | Expr::Variable(_) => (expr.clone(), 1),
+        Expr::Unary(op, operand) => {
+            let (operand, nodes) = expand_counted(operand, frame, scope, limits)?;
+            (Expr::unary(*op, operand), nodes + 1)
+        }
+        Expr::Binary(op, lhs, rhs) => {
+            let (lhs, lhs_nodes) = expand_counted(lhs, frame, scope, limits)?;
+            let (rhs, rhs_nodes) = expand_counted(rhs, frame, scope, limits)?;
+            (Expr::binary(*op, lhs, rhs), lhs_nodes + rhs_nodes + 1)
+        }
+        Expr::Call(name, args) if name == DIFF => match args.as_slice() {
+            [expr, Expr::Variable(variable)] => {
+                let (expanded, _) = expand_counted(expr, frame, scope, limits)?;
+                counted(expanded.derivative_with_limits(variable, limits)?)
+            }
+            _ => return Err(EvalError::UnknownFunction(name.clone(), args.len())),
+        },
+        Expr::Call(name, args) => {
+            let mut nodes = 1;
+            let args = args
+                .iter()
+                .map(|arg| {
+                    let (arg, arg_nodes) = expand_counted(arg, frame, scope, limits)?;
+                    nodes += arg_nodes;
+                    Ok(arg)
+                })
+                .collect::<Result<Vec<_>, EvalError>>()?;
+            let base_name = name.trim_end_matches('\'');
+            if frame.is_compiling(base_name) {
+                return Err(EvalError::RecursiveCall(base_name.to_string()));
+            }
+            if let Some(function) = scope.get(base_name) {
+                if function.variables().len() != args.len() {
+                    return Err(EvalError::UnknownFunction(name.clone(), args.len()));
+                }
+                let callee = Frame {
+                    name: Some(&function.name),
+                    arguments: Some((function.variables(), 0)),
+                    caller: Some(frame),
+                };
+                let (mut body, _) = expand_counted(function.expr(), &callee, scope, limits)?;
+                // Every prime differentiates with respect to the first variable.
+                for _ in base_name.len()..name.len() {
+                    let variable = function
+                        .variables()
+                        .first()
+                        .ok_or_else(|| EvalError::NotDifferentiable(name.clone()))?;
+                    body = body.derivative_with_limits(variable, limits)?;
+                }
+                counted(substitute(body, function.variables(), &args, scope))
+            } else if scope.is_undefined(base_name) {
+                return Err(EvalError::DependencyError(base_name.to_string()));
+            } else if Builtin::from_name(name)
+                .is_some_and(|builtin| builtin.arity().contains(&args.len()))
+            {
+                (Expr::Call(name.clone(), args), nodes)
+            } else {
+                return Err(EvalError::UnknownFunction(name.clone(), args.len()));
+            }
+        }
+    };
+    if nodes > limits.max_steps {
+        return Err(EvalError::StepLimit(limits.max_steps));
+    }
+    Ok((expanded, nodes))
+}
+
+/// `expr` together with how many nodes it has.
+fn counted(expr: Expr) -> (Expr, usize) {
+    let mut nodes = 0;
+    expr.walk(&mut |_| nodes += 1);
+    (expr, nodes)
+}
+
+/// `body` with every one of `variables` replaced by the argument at the same index,
+/// and every parameter from `scope` by its value, so none of them can be mistaken for a variable of the caller.
+fn substitute(body: Expr, variables: &[String], args: &[Expr], scope: &Scope) -> Expr {
+    body.transform(&mut |expr| match expr {
+        Expr::Variable(name) => match variables.iter().position(|var| *var == name) {
+            Some(i) => args[i].clone(),
+            None => scope
+                .parameter(&name)
+                .map_or(Expr::Variable(name), Expr::Number),
+        },
+        other => other,
+    })
+}
+
+/// Applies `op` to every pair of values, leaving the results in `lhs`.
+///
+/// Results that [`BinaryOp::checked_apply`] would fail for become NaN, which every later operation keeps.
+fn binary_columns(op: BinaryOp, lhs: &mut [f64], rhs: &[f64]) {
+    let defined = |value: f64| if value.is_finite() { value } else { f64::NAN };
+    let pairs = lhs.iter_mut().zip(rhs);
+    // A separate loop for every operator, simple enough for the compiler to vectorize.
+    match op {
+        BinaryOp::Add => pairs.for_each(|(lhs, rhs)| *lhs = defined(*lhs + rhs)),
+        BinaryOp::Subtract => pairs.for_each(|(lhs, rhs)| *lhs = defined(*lhs - rhs)),
+        BinaryOp::Multiply => pairs.for_each(|(lhs, rhs)| *lhs = defined(*lhs * rhs)),
+        BinaryOp::Divide => pairs.for_each(|(lhs, rhs)| *lhs = defined(*lhs / rhs)),
+        // Powers can turn NaN into a number, like `NaN^0`, so it has to be kept explicitly.
+        BinaryOp::Pow => pairs.for_each(|(lhs, rhs)| {
+            *lhs = if lhs.is_nan() || rhs.is_nan() {
+                f64::NAN
+            } else {
+                defined(op.apply(*lhs, *rhs))
+            }
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::{EvalError, Expr, Function, Limits, Program, Scope};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn matches_tree_evaluation() {
+        let inputs = [
+            "f(x)=2x^2+1",
+            "f(x)=-x^2-3/x",
+            "f(x)=sin(2x)+max(x,-x,1)+log(8,2)",
+            "f(x)=2^3^x-pi*e",
+            "f(x)=ln(x)+sqrt(x)",
+            "f(x)=0^x",
+            // Undefined steps that a later one turns finite still fail.
+            "f(x)=atan(1/x)+exp(ln(x))",
+        ];
+        for input in inputs {
+            let func = Function::try_from(input).unwrap();
+            let program =
+                Program::compile(func.expr(), func.variables(), &Limits::default()).unwrap();
+            for x in [-2., -0.5, 0., 0.5, 1., 3.] {
+                let map = HashMap::from([("x".to_string(), x)]);
+                assert_eq!(
+                    program.evaluate(&[x]),
+                    func.expr().evaluate(&map),
+                    "{input} at {x}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn slots_and_compile_errors() {
+        let func = Function::try_from("f(a,b)=a-2b").unwrap();
+        let program = Program::compile(func.expr(), func.variables(), &Limits::default()).unwrap();
+        assert_eq!(program.slots(), ["a", "b"]);
+        assert_eq!(program.evaluate(&[1., 3.]), Ok(-5.));
+        let mut stack = Vec::new();
+        assert_eq!(program.evaluate_with_stack(&[3., 1.], &mut stack), Ok(1.));
+
+        assert_eq!(
+            Program::compile(func.expr(), &["a".to_string()], &Limits::default()),
+            Err(EvalError::UnknownVariable("b".to_string()))
+        );
+        assert_eq!(
+            Program::compile(
+                &Expr::Call("nope".to_string(), Vec::new()),
+                &[],
+                &Limits::default()
+            ),
+            Err(EvalError::UnknownFunction("nope".to_string(), 0))
+        );
+
+        let deep = (0..2000).fold(Expr::Number(1.), |expr, _| {
+            Expr::unary(crate::parse::UnaryOp::Negate, expr)
+        });
+        assert_eq!(
+            Program::compile(&deep, &[], &Limits::default()),
+            Err(EvalError::RecursionLimit)
+        );
+
+        let limits = Limits {
+            max_steps: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            Program::compile(func.expr(), func.variables(), &limits),
+            Err(EvalError::StepLimit(2))
+        );
+
+        let shallow = Limits {
+            max_evaluation_depth: 10,
+            ..Default::default()
+        };
+        let nested = (0..20).fold(Expr::Variable("x".to_string()), |expr, _| {
+            Expr::unary(crate::parse::UnaryOp::Negate, expr)
+        });
+        assert_eq!(
+            Program::compile(&nested, &["x".to_string()], &shallow),
+            Err(EvalError::RecursionLimit)
+        );
+        assert_eq!(
+            nested.evaluate_with_limits(&HashMap::from([("x".to_string(), 1.)]), &shallow),
+            Err(EvalError::RecursionLimit)
+        );
+        assert_eq!(
+            nested.derivative_with_limits("x", &shallow),
+            Err(EvalError::RecursionLimit)
+        );
+        assert!(Program::compile(&nested, &["x".to_string()], &Limits::default()).is_ok());
+    }
+
+    #[test]
+    fn batch_matches_single_evaluation() {
+        let xs = (0..1000).map(|i| -5. + i as f64 / 100.).collect::<Vec<_>>();
+        for input in [
+            "f(x)=2x^2+1",
+            "f(x)=-x^2-3/x",
+            "f(x)=sin(2x)+max(x,-x,1)+log(8,2)",
+            "f(x)=ln(x)^0+sqrt(x)",
+            "f(x)=(x-1)^(-1)",
+        ] {
+            let func = Function::try_from(input).unwrap();
+            let program = func.program().unwrap();
+            let mut output = vec![0.; xs.len()];
+            program.evaluate_batch(&[&xs], &mut output);
+            for (x, y) in xs.iter().zip(output) {
+                match program.evaluate(&[*x]) {
+                    Ok(expected) => assert_eq!(y, expected, "{input} at {x}"),
+                    Err(_) => assert!(y.is_nan(), "{input} at {x}"),
+                }
+            }
+        }
+
+        let func = Function::try_from("f(a,b)=a-2b").unwrap();
+        let mut output = [0.; 3];
+        func.y_batch(&[&[1., 2., 3.], &[0., 1., 2.]], &mut output)
+            .unwrap();
+        assert_eq!(output, [1., 0., -1.]);
+        assert_eq!(
+            func.y_batch(&[&[1., 2., 3.]], &mut output),
+            Err(EvalError::WrongColumnCount(2, 1))
+        );
+        assert_eq!(
+            func.y_batch(&[&[1., 2., 3.], &[0., 1.]], &mut output),
+            Err(EvalError::WrongColumnLength(3))
+        );
+    }
+
+    #[test]
+    fn inlined_calls() {
+        let limits = Limits::default();
+        let mut scope = Scope::new();
+        for input in ["f(x)=1/x", "g(a,b)=a^b", "h(x)=g(f(x),2)-x"] {
+            scope.define(Arc::new(
+                Function::parse_in(input, &scope, &limits).unwrap(),
+            ));
+        }
+        let func = Function::parse_in("k(x)=h(x+1)*f(x)", &scope, &limits).unwrap();
+        let program = func.program().unwrap();
+        let expected = |x: f64| ((1. / (x + 1.)).powi(2) - (x + 1.)) * (1. / x);
+        assert_eq!(program.evaluate(&[1.]), Ok(expected(1.)));
+        assert_eq!(program.evaluate(&[0.]), Err(EvalError::DivisionByZero));
+
+        let xs = [-2., 0., 0.5, 3.];
+        let mut output = [0.; 4];
+        program.evaluate_batch(&[&xs], &mut output);
+        assert_eq!(output[0], expected(-2.));
+        assert!(output[1].is_nan());
+        assert_eq!(output[2..], [expected(0.5), expected(3.)]);
+
+        // Every call doubles the size of the inlined body.
+        let mut scope = Scope::new();
+        scope.define(Arc::new(Function::try_from("a(x)=x+x").unwrap()));
+        for (name, callee) in ('b'..='t').zip('a'..) {
+            let input = format!("{name}(x)={callee}(x)+{callee}(x)");
+            scope.define(Arc::new(
+                Function::parse_in(&input, &scope, &limits).unwrap(),
+            ));
+        }
+        assert_eq!(
+            Function::parse_in("u(x)=t(x)", &scope, &limits)
+                .unwrap()
+                .program(),
+            Err(EvalError::StepLimit(limits.max_steps))
+        );
+    }
+}
@@ -0,0 +1,687 @@
+use crate::parse::{
+    Builtin, Constant, DependencyGraph, EvalError, Function, Limits, ParseError, Program, Scope,
+    TokenQueue,
+};
+use itertools::Itertools;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, LazyLock};
+
+static PARAMETER_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^\s*(?<ParameterName>[a-z]+)\s*=\s*(?<Expression>.*\S)\s*$")
+        .expect("Regex should compile")
+});
+
+/// A named number that every function can use, defined like `a=2`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parameter {
+    pub name: String,
+    pub value: f64,
+}
+
+impl Parameter {
+    /// Whether `input` is shaped like a parameter definition, whether it is a valid one or not.
+    pub fn is_parameter(input: &str) -> bool {
+        PARAMETER_REGEX.is_match(input)
+    }
+
+    /// Whether `input` defines a parameter as just a number, like `a=-2.5`, rather than an expression.
+    pub fn is_literal(input: &str) -> bool {
+        PARAMETER_REGEX
+            .captures(input)
+            .is_some_and(|captures| captures["Expression"].parse::<f64>().is_ok())
+    }
+
+    /// Parses a parameter like `a=2` or `r=sqrt(2)/2`, whose value can't depend on any variable.
+    pub fn parse(input: &str) -> Result<Self, ParseError> {
+        Self::parse_with_limits(input, &Limits::default())
+    }
+
+    /// Parses a parameter like [`Parameter::parse`], failing if it is larger than `limits` allow.
+    pub fn parse_with_limits(input: &str, limits: &Limits) -> Result<Self, ParseError> {
+        Self::parse_in(input, &Scope::new(), limits)
+    }
+
+    /// Parses a parameter like [`Parameter::parse_with_limits`], whose value may use the parameters in `scope`,
+    /// like `b=a+1`.
+    pub fn parse_in(input: &str, scope: &Scope, limits: &Limits) -> Result<Self, ParseError> {
+        let captures = PARAMETER_REGEX
+            .captures(input)
+            .ok_or(ParseError::NoFunctionDefined)?;
+        let name = &captures["ParameterName"];
+        let ex = captures
+            .name("Expression")
+            .ok_or(ParseError::UnableToFind("parameter value".to_string()))?;
+        let tokens = TokenQueue::in_scope(ex.as_str(), &[], scope, limits)
+            .map_err(|e| e.offset(ex.start()))?;
+        let value = Program::compile_function(None, tokens.expr(), &[], scope, limits)
+            .and_then(|program| program.evaluate(&[]))
+            .map_err(|e| ParseError::InvalidValue(e, ex.range()))?;
+        Ok(Self {
+            name: name.to_string(),
+            value,
+        })
+    }
+
+    /// The names of the parameters the value of `input` uses, out of the ones in `scope`,
+    /// or nothing if it can't be parsed.
+    fn uses(input: &str, scope: &Scope, limits: &Limits) -> Vec<String> {
+        PARAMETER_REGEX
+            .captures(input)
+            .and_then(|captures| {
+                TokenQueue::in_scope(&captures["Expression"], &[], scope, limits).ok()
+            })
+            .map_or_else(Vec::new, |tokens| {
+                tokens
+                    .expr()
+                    .variables()
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            })
+    }
+}
+
+/// A bare expression like `2^10/3` or `f(2)`, calculated right away.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calculation {
+    pub value: Result<f64, EvalError>,
+}
+
+impl Calculation {
+    /// Whether `input` is a bare expression, without a `=` defining anything.
+    pub fn is_calculation(input: &str) -> bool {
+        !input.contains('=') && !input.trim().is_empty()
+    }
+
+    /// Parses and calculates an expression without any variables, like `sin(pi/4)`.
+    pub fn parse(input: &str) -> Result<Self, ParseError> {
+        Self::parse_in(input, &Scope::new(), &Limits::default())
+    }
+
+    /// Parses and calculates an expression that may use the functions and parameters in `scope`, like `f(2)+a`.
+    pub fn parse_in(input: &str, scope: &Scope, limits: &Limits) -> Result<Self, ParseError> {
+        let tokens = TokenQueue::in_scope(input, &[], scope, limits)?;
+        let value = Program::compile_function(None, tokens.expr(), &[], scope, limits)
+            .and_then(|program| program.evaluate(&[]));
+        Ok(Self { value })
+    }
+}
+
+/// What a single input of the app defines.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Definition {
+    Function(Arc<Function>),
+    Parameter(Parameter),
+    Calculation(Calculation),
+}
+
+impl Definition {
+    /// The name the definition can be used by in other definitions, if it has one.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Self::Function(function) => Some(&function.name),
+            Self::Parameter(parameter) => Some(&parameter.name),
+            Self::Calculation(_) => None,
+        }
+    }
+
+    /// Parses a list of definitions that may use each other, in any order, like the inputs of the app.
+    ///
+    /// Besides `f(x)=...`, functions of `x` can be written as `f=...`, or without a name as `y=...` or just `...`,
+    /// in which case they are named `f`, `g`, `h` and so on, skipping the names already used.
+    /// Anything else written as `a=...` is a [`Parameter`], and anything else without `=` a [`Calculation`].
+    ///
+    /// Parameters come first, so every function can use them, each after the parameters it uses.
+    /// Functions are parsed after the functions they call, see [`DependencyGraph::order`],
+    /// and calculations last, so they can use every function.
+    /// Functions calling themselves, directly or through others, fail with [`ParseError::CircularDefinition`],
+    /// and calling a function with an error fails with [`EvalError::DependencyError`] when calculated.
+    ///
+    /// When several definitions have the same name, the first one is used.
+    pub fn parse_all(inputs: &[impl AsRef<str>], limits: &Limits) -> Vec<Result<Self, ParseError>> {
+        let inputs = inputs.iter().map(AsRef::as_ref).collect_vec();
+        let forms = Form::of_all(&inputs);
+        let names = automatic_names(&inputs, &forms);
+
+        let mut parsed = vec![None; inputs.len()];
+        let mut scope = Scope::new();
+        // Parameters are parsed after the parameters they use, the same way functions are below.
+        let mut parameters = HashMap::new();
+        for (i, form) in forms.iter().enumerate() {
+            if let Form::Parameter = form {
+                if let Some(captures) = PARAMETER_REGEX.captures(inputs[i]) {
+                    let name = captures.name("ParameterName").expect("Is in the regex");
+                    parameters.entry(name.as_str()).or_insert(i);
+                }
+            }
+        }
+        for name in parameters.keys() {
+            scope.set_parameter(name, 0.);
+        }
+        let uses = (0..inputs.len())
+            .map(|i| match forms[i] {
+                Form::Parameter => Parameter::uses(inputs[i], &scope, limits)
+                    .iter()
+                    .filter_map(|name| parameters.get(name.as_str()).copied())
+                    .collect(),
+                _ => Vec::new(),
+            })
+            .collect_vec();
+        let parameter_graph = DependencyGraph::from_calls(uses);
+        let mut scope = Scope::new();
+        for i in parameter_graph.order() {
+            if !matches!(forms[i], Form::Parameter) {
+                continue;
+            }
+            let parameter = Parameter::parse_in(inputs[i], &scope, limits);
+            if let Ok(parameter) = &parameter {
+                if parameters.get(parameter.name.as_str()) == Some(&i) {
+                    scope.set_parameter(&parameter.name, parameter.value);
+                }
+            }
+            parsed[i] = Some(parameter.map(Self::Parameter));
+        }
+        for (i, form) in forms.iter().enumerate() {
+            if matches!(form, Form::Parameter) && parsed[i].is_none() {
+                parsed[i] = Some(match parameter_graph.cycle(i) {
+                    Some(cycle) => Err(ParseError::CircularDefinition(
+                        cycle
+                            .into_iter()
+                            .map(|j| {
+                                PARAMETER_REGEX.captures(inputs[j]).expect("Is a parameter")
+                                    ["ParameterName"]
+                                    .to_string()
+                            })
+                            .collect(),
+                    )),
+                    // Uses a parameter in a cycle, which fails to be found.
+                    None => Parameter::parse_in(inputs[i], &scope, limits).map(Self::Parameter),
+                });
+            }
+        }
+
+        let parse_function = |i: usize, scope: &Scope| match &forms[i] {
+            // It couldn't be called, since `e(x)` would still be the constant times `x`.
+            Form::Shorthand {
+                name: Some(name), ..
+            } if is_reserved(name) => {
+                let start = inputs[i].find(name).expect("The name is part of the input");
+                Err(ParseError::ReservedName(
+                    name.to_string(),
+                    start..start + name.len(),
+                ))
+            }
+            Form::Shorthand {
+                body, body_start, ..
+            } => Function::from_body(
+                names[i].as_deref().expect("Every shorthand is named"),
+                vec![IMPLICIT_VARIABLE.to_string()],
+                body,
+                *body_start,
+                scope,
+                limits,
+            ),
+            _ => Function::parse_in(inputs[i], scope, limits),
+        };
+        // Parse once with every function declared, only to find out which functions call which.
+        for (i, form) in forms.iter().enumerate() {
+            match form {
+                Form::Function => {
+                    if let Some((name, arity)) = Function::declaration(inputs[i]) {
+                        scope.declare(name, arity);
+                    }
+                }
+                Form::Shorthand { .. } => {
+                    let name = names[i].as_deref().expect("Every shorthand is named");
+                    if !is_reserved(name) {
+                        scope.declare(name, 1);
+                    }
+                }
+                Form::Parameter | Form::Calculation => (),
+            }
+        }
+        let declared = (0..inputs.len())
+            .map(|i| {
+                forms[i]
+                    .is_function()
+                    .then(|| parse_function(i, &scope).ok())
+                    .flatten()
+            })
+            .collect_vec();
+        let graph = DependencyGraph::new(&declared.iter().map(Option::as_ref).collect_vec());
+
+        for i in graph.order() {
+            if !forms[i].is_function() {
+                continue;
+            }
+            let function = parse_function(i, &scope).map(Arc::new);
+            if let Ok(function) = &function {
+                scope.define(Arc::clone(function));
+            }
+            parsed[i] = Some(function.map(Self::Function));
+        }
+        // Whatever else is left is a calculation, in a cycle, or calls a function that is.
+        parsed
+            .into_iter()
+            .enumerate()
+            .map(|(i, definition)| {
+                definition.unwrap_or_else(|| match graph.cycle(i) {
+                    _ if matches!(forms[i], Form::Calculation) => {
+                        Calculation::parse_in(inputs[i], &scope, limits).map(Self::Calculation)
+                    }
+                    Some(cycle) => Err(ParseError::CircularDefinition(
+                        cycle
+                            .into_iter()
+                            .map(|j| declared[j].as_ref().expect("Is in the graph").name.clone())
+                            .collect(),
+                    )),
+                    None => {
+                        parse_function(i, &scope).map(|function| Self::Function(Arc::new(function)))
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+/// The variable of functions defined without declaring any, like `y=x^2`.
+const IMPLICIT_VARIABLE: &str = "x";
+
+/// The names given to functions defined without one, in order of preference.
+const AUTOMATIC_NAMES: RangeInclusive<char> = 'f'..='w';
+
+/// How an input defines something, found from its shape before any expression in it is parsed.
+enum Form<'a> {
+    /// `f(x)=...`, or anything else not matching the other forms.
+    Function,
+    /// `f=...` or `y=...` using `x`, or just an expression using `x`, defining a function of `x`,
+    /// with the body starting at byte `body_start` of the input.
+    Shorthand {
+        name: Option<&'a str>,
+        body: &'a str,
+        body_start: usize,
+    },
+    /// `a=...` without using `x`.
+    Parameter,
+    /// An expression without `=` or `x`.
+    Calculation,
+}
+
+impl<'a> Form<'a> {
+    /// The forms of all `inputs`, which are needed together to tell which names they can use.
+    fn of_all(inputs: &[&'a str]) -> Vec<Self> {
+        // Every name that might be defined, so a body only fails to split into names if it is invalid.
+        let mut names = Scope::new();
+        for input in inputs {
+            if let Some((name, arity)) = Function::declaration(input) {
+                names.declare(name, arity);
+            } else if let Some(captures) = PARAMETER_REGEX.captures(input) {
+                names.set_parameter(&captures["ParameterName"], 0.);
+            }
+        }
+        for name in AUTOMATIC_NAMES {
+            names.declare(&name.to_string(), 1);
+        }
+        inputs.iter().map(|input| Self::of(input, &names)).collect()
+    }
+
+    /// The form of `input`, where `names` are the names it can use besides `x`.
+    fn of(input: &'a str, names: &Scope) -> Self {
+        // A body that can't be split into names isn't valid either way, so let it be parsed as a function.
+        let uses_x = |body: &str| {
+            !matches!(
+                TokenQueue::variables_in(body, &[IMPLICIT_VARIABLE.to_string()], names),
+                Ok(used) if used.is_empty()
+            )
+        };
+        if let Some(captures) = PARAMETER_REGEX.captures(input) {
+            let name = captures
+                .name("ParameterName")
+                .expect("Is in the regex")
+                .as_str();
+            let body = captures.name("Expression").expect("Is in the regex");
+            match name {
+                "y" => Self::Shorthand {
+                    name: None,
+                    body: body.as_str(),
+                    body_start: body.start(),
+                },
+                _ if uses_x(body.as_str()) => Self::Shorthand {
+                    name: Some(name),
+                    body: body.as_str(),
+                    body_start: body.start(),
+                },
+                _ => Self::Parameter,
+            }
+        } else if Calculation::is_calculation(input) {
+            if uses_x(input) {
+                Self::Shorthand {
+                    name: None,
+                    body: input,
+                    body_start: 0,
+                }
+            } else {
+                Self::Calculation
+            }
+        } else {
+            Self::Function
+        }
+    }
+
+    fn is_function(&self) -> bool {
+        matches!(self, Self::Function | Self::Shorthand { .. })
+    }
+}
+
+/// Whether `name` is taken by a built-in function or constant, so a function can't be named after it without a declaration.
+fn is_reserved(name: &str) -> bool {
+    Builtin::from_name(name).is_some() || Constant::from_name(name).is_some()
+}
+
+/// The names of the shorthand functions in `forms`, giving the ones without a name the first name not used
+/// by any other input.
+fn automatic_names(inputs: &[&str], forms: &[Form]) -> Vec<Option<String>> {
+    let taken = inputs
+        .iter()
+        .zip(forms)
+        .filter_map(|(input, form)| match form {
+            Form::Function => Function::declaration(input).map(|(name, _)| name),
+            Form::Shorthand { name, .. } => *name,
+            Form::Parameter => PARAMETER_REGEX
+                .captures(input)
+                .and_then(|captures| Some(captures.name("ParameterName")?.as_str())),
+            Form::Calculation => None,
+        })
+        .collect::<HashSet<_>>();
+    // Once the single letters run out, longer names of the same letters, like `ff` and `fg`.
+    let longer = (2..).flat_map(|len| {
+        std::iter::repeat_n(AUTOMATIC_NAMES, len)
+            .multi_cartesian_product()
+            .map(String::from_iter)
+    });
+    let mut free = AUTOMATIC_NAMES
+        .map(String::from)
+        .chain(longer)
+        .filter(|name| !taken.contains(name.as_str()) && !is_reserved(name));
+    forms
+        .iter()
+        .map(|form| match form {
+            Form::Shorthand { name: None, .. } => free.next(),
+            Form::Shorthand {
+                name: Some(name), ..
+            } => Some(name.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::{Calculation, Definition, EvalError, Limits, Parameter, ParseError};
+    use std::collections::HashMap;
+
+    fn at(definition: &Result<Definition, ParseError>, x: f64) -> Result<f64, EvalError> {
+        match definition {
+            Ok(Definition::Function(function)) => {
+                function.y_pos(&HashMap::from([("x".to_string(), x)]))
+            }
+            other => panic!("Expected a function, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parameters() {
+        assert_eq!(
+            Parameter::parse("a=2"),
+            Ok(Parameter {
+                name: "a".to_string(),
+                value: 2.
+            })
+        );
+        assert_eq!(Parameter::parse("r=-sqrt(4)/4").unwrap().value, -0.5);
+        assert_eq!(
+            Parameter::parse("a=2x"),
+            Err(ParseError::UnknownVariable("x".to_string(), 3..4))
+        );
+        assert_eq!(
+            Parameter::parse("a=1/0"),
+            Err(ParseError::InvalidValue(EvalError::DivisionByZero, 2..5))
+        );
+        assert!(!Parameter::is_parameter("f(x)=2x"));
+        assert!(Parameter::is_literal("a = -2.5"));
+        assert!(!Parameter::is_literal("r=sqrt(2)/2"));
+        assert_eq!(
+            Parameter::parse(" a = 1/0 "),
+            Err(ParseError::InvalidValue(EvalError::DivisionByZero, 5..8))
+        );
+
+        let parsed = Definition::parse_all(
+            &["f(x)=a*x^2+g(x)", "a=3", "g(x)=ax+b", "b=1", "a=5"],
+            &Limits::default(),
+        );
+        assert_eq!(at(&parsed[0], 2.), Ok(12. + 7.));
+        assert_eq!(at(&parsed[2], 2.), Ok(7.));
+        assert_eq!(parsed[4].as_ref().unwrap().name(), Some("a"));
+
+        // Parameters can use each other, in any order.
+        let parsed = Definition::parse_all(
+            &["f(x)=b*x", "b=a+1", "a=2", "c=d", "d=c+1", "g=c*2"],
+            &Limits::default(),
+        );
+        assert_eq!(at(&parsed[0], 2.), Ok(6.));
+        assert_eq!(
+            parsed[1].as_ref().unwrap(),
+            &Definition::Parameter(Parameter {
+                name: "b".to_string(),
+                value: 3.
+            })
+        );
+        assert_eq!(
+            parsed[3],
+            Err(ParseError::CircularDefinition(vec![
+                "c".to_string(),
+                "d".to_string(),
+                "c".to_string()
+            ]))
+        );
+        assert_eq!(
+            parsed[5],
+            Err(ParseError::UnknownVariable("c".to_string(), 2..3))
+        );
+
+        // Declared variables shadow parameters.
+        let parsed = Definition::parse_all(&["a=3", "f(a)=2a"], &Limits::default());
+        let Ok(Definition::Function(function)) = &parsed[1] else {
+            panic!("Expected a function, got {:?}", parsed[1]);
+        };
+        assert_eq!(
+            function.y_pos(&HashMap::from([("a".to_string(), 1.)])),
+            Ok(2.)
+        );
+    }
+
+    #[test]
+    fn calculations() {
+        let value = |input: &str| Calculation::parse(input).map(|calculation| calculation.value);
+        assert_eq!(value("2^10/4"), Ok(Ok(256.)));
+        let sine = value("sin(pi/4)").unwrap().unwrap();
+        assert!((sine - std::f64::consts::FRAC_1_SQRT_2).abs() < 1e-15);
+        assert_eq!(value("1/0"), Ok(Err(EvalError::DivisionByZero)));
+        assert_eq!(
+            value("2x"),
+            Err(ParseError::UnknownVariable("x".to_string(), 1..2))
+        );
+        assert!(!Calculation::is_calculation("a=2"));
+        assert!(!Calculation::is_calculation(" "));
+
+        let parsed = Definition::parse_all(
+            &["f(2)+a", "f(x)=x^2", "a=1", "", "g(x)=g(x)", "g(1)"],
+            &Limits::default(),
+        );
+        assert_eq!(
+            parsed[0],
+            Ok(Definition::Calculation(Calculation { value: Ok(5.) }))
+        );
+        assert_eq!(parsed[3], Err(ParseError::NoFunctionDefined));
+        assert_eq!(
+            parsed[5],
+            Ok(Definition::Calculation(Calculation {
+                value: Err(EvalError::DependencyError("g".to_string()))
+            }))
+        );
+    }
+
+    #[test]
+    fn parse_all_in_dependency_order() {
+        let parsed = Definition::parse_all(
+            &["h(x)=g(x)+f(x)", "g(x)=f(x)^2", "f(x)=2x"],
+            &Limits::default(),
+        );
+        assert_eq!(at(&parsed[0], 3.), Ok(42.));
+        assert_eq!(at(&parsed[1], 3.), Ok(36.));
+
+        let parsed = Definition::parse_all(
+            &[
+                "f(x)=g(x)",
+                "g(x)=f(x)+1",
+                "k(x)=g(x)",
+                "s(x)=2x+)",
+                "t(x)=s(x)",
+            ],
+            &Limits::default(),
+        );
+        assert_eq!(
+            parsed[0],
+            Err(ParseError::CircularDefinition(vec![
+                "f".to_string(),
+                "g".to_string(),
+                "f".to_string()
+            ]))
+        );
+        assert_eq!(
+            parsed[1].as_ref().unwrap_err().to_string(),
+            "Definition depends on itself: g → f → g"
+        );
+        assert_eq!(
+            at(&parsed[2], 0.),
+            Err(EvalError::DependencyError("g".to_string()))
+        );
+        assert_eq!(parsed[3], Err(ParseError::InvalidTokenPosition(8..9)));
+        assert_eq!(
+            at(&parsed[4], 0.),
+            Err(EvalError::DependencyError("s".to_string()))
+        );
+
+        assert_eq!(
+            Definition::parse_all(&["f(x)=f(x)"], &Limits::default()),
+            [Err(ParseError::CircularDefinition(vec![
+                "f".to_string(),
+                "f".to_string()
+            ]))]
+        );
+    }
+
+    #[test]
+    fn shorthand_functions() {
+        let parsed = Definition::parse_all(
+            &[
+                "y=x^2", "f(x)=x+1", "a=2", "p=ax", "2x+f(x)", "2^10", "y=x+)", "g(p(1))",
+            ],
+            &Limits::default(),
+        );
+        let name = |i: usize| parsed[i].as_ref().ok().and_then(Definition::name);
+        assert_eq!(name(0), Some("g"));
+        assert_eq!(at(&parsed[0], 3.), Ok(9.));
+        assert_eq!(name(2), Some("a"));
+        assert_eq!(name(3), Some("p"));
+        assert_eq!(at(&parsed[3], 3.), Ok(6.));
+        assert_eq!(name(4), Some("h"));
+        assert_eq!(at(&parsed[4], 1.), Ok(4.));
+        assert_eq!(
+            parsed[5],
+            Ok(Definition::Calculation(Calculation { value: Ok(1024.) }))
+        );
+        assert_eq!(parsed[6], Err(ParseError::InvalidTokenPosition(4..5)));
+        assert_eq!(
+            parsed[7],
+            Ok(Definition::Calculation(Calculation { value: Ok(4.) }))
+        );
+
+        // Once every letter is taken, the names get longer, and can still be called.
+        let inputs = (0..19)
+            .map(|i| format!("x+{i}"))
+            .chain(
+                [
+                    "ff(2)",
+                    "F(x)=2x",
+                    "f_a(x)=x",
+                    "F(3)+f_a(1)+f1(1)",
+                    "f1(x)=x",
+                ]
+                .map(String::from),
+            )
+            .collect::<Vec<_>>();
+        let parsed = Definition::parse_all(
+            &inputs.iter().map(String::as_str).collect::<Vec<_>>(),
+            &Limits::default(),
+        );
+        assert_eq!(
+            parsed[17].as_ref().ok().and_then(Definition::name),
+            Some("w")
+        );
+        assert_eq!(
+            parsed[18].as_ref().ok().and_then(Definition::name),
+            Some("ff")
+        );
+        assert_eq!(
+            parsed[19],
+            Ok(Definition::Calculation(Calculation { value: Ok(20.) }))
+        );
+        // Explicitly defined functions can have other names too, as long as they can be called.
+        assert_eq!(
+            parsed[22],
+            Ok(Definition::Calculation(Calculation { value: Ok(8.) }))
+        );
+    }
+
+    #[test]
+    fn reserved_shorthand_names() {
+        let parsed = Definition::parse_all(&["e=x+1", "g(x)=e^x", " sin = 2x"], &Limits::default());
+        assert_eq!(
+            parsed[0],
+            Err(ParseError::ReservedName("e".to_string(), 0..1))
+        );
+        assert_eq!(at(&parsed[1], 0.), Ok(1.));
+        assert_eq!(
+            parsed[2],
+            Err(ParseError::ReservedName("sin".to_string(), 1..4))
+        );
+    }
+
+    #[test]
+    fn whitespace_in_definitions() {
+        let parsed = Definition::parse_all(
+            &[
+                "a = 2",
+                "y = a x^2",
+                " f( x , y ) = x + y ",
+                "g(x) = x+)",
+                "a + 1",
+            ],
+            &Limits::default(),
+        );
+        assert_eq!(parsed[0].as_ref().unwrap().name(), Some("a"));
+        assert_eq!(at(&parsed[1], 3.), Ok(18.));
+        let Ok(Definition::Function(function)) = &parsed[2] else {
+            panic!("Expected a function, got {:?}", parsed[2]);
+        };
+        assert_eq!(function.variables(), ["x", "y"]);
+        assert_eq!(parsed[3], Err(ParseError::InvalidTokenPosition(9..10)));
+        assert_eq!(
+            parsed[4],
+            Ok(Definition::Calculation(Calculation { value: Ok(3.) }))
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use crate::parse::Function;
+use std::collections::{HashMap, VecDeque};
+
+/// Which functions in a list of definitions call which,
+/// so they can be parsed in an order where every function comes after the ones it calls.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DependencyGraph {
+    /// For every definition, the indices of the definitions it calls.
+    calls: Vec<Vec<usize>>,
+}
+
+impl DependencyGraph {
+    /// Builds the graph of `definitions`, where `None` is a definition that couldn't be parsed.
+    ///
+    /// When several definitions have the same name, calls go to the first one.
+    pub fn new(definitions: &[Option<&Function>]) -> Self {
+        let mut indices = HashMap::new();
+        for (i, function) in definitions.iter().enumerate() {
+            if let Some(function) = function {
+                indices.entry(function.name.as_str()).or_insert(i);
+            }
+        }
+        let calls = definitions
+            .iter()
+            .map(|function| {
+                function.map_or_else(Vec::new, |function| {
+                    function
+                        .calls()
+                        .into_iter()
+                        .filter_map(|name| indices.get(name).copied())
+                        .collect()
+                })
+            })
+            .collect();
+        Self { calls }
+    }
+
+    /// Builds the graph from the indices of the definitions every definition uses,
+    /// for definitions that aren't functions, like parameters using other parameters.
+    pub(crate) fn from_calls(calls: Vec<Vec<usize>>) -> Self {
+        Self { calls }
+    }
+
+    /// The indices of the definitions called by the one at `index`.
+    pub fn calls(&self, index: usize) -> &[usize] {
+        &self.calls[index]
+    }
+
+    /// The definitions in an order where each comes after every definition it calls,
+    /// and otherwise in the order they were given.
+    ///
+    /// Definitions in a cycle, and those calling them, are left out since they have no such order.
+    pub fn order(&self) -> Vec<usize> {
+        let mut done = vec![false; self.calls.len()];
+        let mut order = Vec::new();
+        loop {
+            let ready = (0..self.calls.len())
+                .filter(|&i| !done[i] && self.calls[i].iter().all(|&callee| done[callee]))
+                .collect::<Vec<_>>();
+            if ready.is_empty() {
+                return order;
+            }
+            for i in ready {
+                done[i] = true;
+                order.push(i);
+            }
+        }
+    }
+
+    /// The shortest chain of calls from the definition at `index` back to itself,
+    /// starting and ending with `index`, or `None` if it isn't part of a cycle.
+    pub fn cycle(&self, index: usize) -> Option<Vec<usize>> {
+        let mut caller = vec![None; self.calls.len()];
+        let mut unvisited = VecDeque::from([index]);
+        while let Some(current) = unvisited.pop_front() {
+            for &callee in &self.calls[current] {
+                if callee == index {
+                    let mut cycle = vec![index];
+                    let mut link = Some(current);
+                    while let Some(i) = link.filter(|&i| i != index) {
+                        cycle.push(i);
+                        link = caller[i];
+                    }
+                    cycle[1..].reverse();
+                    cycle.push(index);
+                    return Some(cycle);
+                }
+                if caller[callee].is_none() {
+                    caller[callee] = Some(current);
+                    unvisited.push_back(callee);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::{DependencyGraph, Function, Limits, Scope};
+
+    fn graph(inputs: &[&str]) -> DependencyGraph {
+        let mut scope = Scope::new();
+        scope.declare("f", 1);
+        scope.declare("g", 1);
+        scope.declare("h", 1);
+        let functions = inputs
+            .iter()
+            .map(|input| Function::parse_in(input, &scope, &Limits::default()).ok())
+            .collect::<Vec<_>>();
+        DependencyGraph::new(&functions.iter().map(Option::as_ref).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn order_and_cycles() {
+        let chain = graph(&["h(x)=g(x)+f(x)", "g(x)=f(x)^2", "f(x)=2x", "nope"]);
+        assert_eq!(chain.calls(0), [1, 2]);
+        assert_eq!(chain.order(), [2, 3, 1, 0]);
+        assert_eq!(chain.cycle(0), None);
+
+        let cyclic = graph(&[
+            "f(x)=g(x)",
+            "g(x)=h(x)+1",
+            "h(x)=f(x)",
+            "k(x)=h(x)",
+            "s(x)=x",
+        ]);
+        assert_eq!(cyclic.order(), [4]);
+        assert_eq!(cyclic.cycle(0), Some(vec![0, 1, 2, 0]));
+        assert_eq!(cyclic.cycle(1), Some(vec![1, 2, 0, 1]));
+        assert_eq!(cyclic.cycle(3), None);
+
+        let itself = graph(&["f(x)=f(x-1)"]);
+        assert_eq!(itself.order(), []);
+        assert_eq!(itself.cycle(0), Some(vec![0, 0]));
+    }
+}
@@ -0,0 +1,288 @@
+use crate::parse::builtins::{Builtin, Constant};
+use crate::parse::expr::{BinaryOp, Expr, UnaryOp};
+use crate::parse::limits::Limits;
+use crate::parse::math_functions::EvalError;
+
+/// The name of the call that `diff(x^2,x)` and `d/dx(x^2)` are parsed into,
+/// standing for the derivative of its first argument with respect to the variable in its second.
+pub(crate) const DIFF: &str = "diff";
+
+/// Whether the call `name` stands for a derivative, either [`DIFF`] or a function with primes like `f'`.
+pub(crate) fn is_derivative(name: &str) -> bool {
+    name == DIFF || name.ends_with('\'')
+}
+
+impl Expr {
+    /// The derivative of the expression with respect to `variable`, found with the rules of differentiation.
+    ///
+    /// The expression may only call built-in functions, so calls to other functions have to be inlined first,
+    /// like [`Program`](crate::parse::Program) does when compiling a derivative.
+    /// The result is [simplified](Expr::simplified), and parts that obviously make no difference,
+    /// like adding `0` or multiplying by `1`, are already left out as it is built, so it doesn't grow needlessly.
+    ///
+    /// # Errors
+    ///
+    /// [`EvalError::NotDifferentiable`] for calls to functions without a derivative, like `max`.
+    pub fn derivative(&self, variable: &str) -> Result<Expr, EvalError> {
+        self.derivative_with_limits(variable, &Limits::default())
+    }
+
+    /// [`Expr::derivative`], which fails with [`EvalError::RecursionLimit`] if the expression
+    /// is nested deeper than `limits` allow.
+    pub fn derivative_with_limits(
+        &self,
+        variable: &str,
+        limits: &Limits,
+    ) -> Result<Expr, EvalError> {
+        self.derivative_at_depth(variable, limits.max_evaluation_depth)
+            .map(|derivative| derivative.simplified_with_limits(limits))
+    }
+
+    /// Whether the value of the expression changes with `variable`.
+    fn depends_on(&self, variable: &str) -> bool {
+        self.variables().contains(&variable)
+    }
+
+    /// The derivative, with `depth_left` levels of nesting left before it is too deep to work out.
+    fn derivative_at_depth(&self, variable: &str, depth_left: usize) -> Result<Expr, EvalError> {
+        if depth_left == 0 {
+            return Err(EvalError::RecursionLimit);
+        }
+        let d = |expr: &Expr| expr.derivative_at_depth(variable, depth_left - 1);
+        Ok(match self {
+            Self::Number(_) | Self::Constant(_) => Self::Number(0.),
+            Self::Variable(name) => Self::Number(if name == variable { 1. } else { 0. }),
+            Self::Unary(UnaryOp::Negate, operand) => negate(d(operand)?),
+            Self::Binary(op, lhs, rhs) => {
+                let (u, v) = (lhs.as_ref().clone(), rhs.as_ref().clone());
+                let (du, dv) = (d(lhs)?, d(rhs)?);
+                match op {
+                    BinaryOp::Add => add(du, dv),
+                    BinaryOp::Subtract => subtract(du, dv),
+                    BinaryOp::Multiply => add(multiply(du, v), multiply(u, dv)),
+                    BinaryOp::Divide => divide(
+                        subtract(multiply(du, v.clone()), multiply(u, dv)),
+                        pow(v, Self::Number(2.)),
+                    ),
+                    BinaryOp::Pow if !rhs.depends_on(variable) => multiply(
+                        multiply(v.clone(), pow(u, subtract(v, Self::Number(1.)))),
+                        du,
+                    ),
+                    // d/dx e^v = e^v * v', since ln(e) is 1.
+                    BinaryOp::Pow if *lhs.as_ref() == Self::Constant(Constant::E) => {
+                        multiply(self.clone(), dv)
+                    }
+                    BinaryOp::Pow if !lhs.depends_on(variable) => {
+                        multiply(multiply(self.clone(), call(Builtin::Ln, u)), dv)
+                    }
+                    // d/dx u^v = u^v * (v'*ln(u) + v*u'/u)
+                    BinaryOp::Pow => multiply(
+                        self.clone(),
+                        add(
+                            multiply(dv, call(Builtin::Ln, u.clone())),
+                            divide(multiply(v, du), u),
+                        ),
+                    ),
+                }
+            }
+            Self::Call(name, args) => {
+                let builtin = Builtin::from_name(name)
+                    .filter(|builtin| builtin.arity().contains(&args.len()))
+                    .ok_or_else(|| EvalError::NotDifferentiable(name.clone()))?;
+                let u = args[0].clone();
+                let square = |expr: Expr| pow(expr, Self::Number(2.));
+                // The derivative of the outer function, to be multiplied by the inner one's by the chain rule.
+                let outer = match builtin {
+                    Builtin::Sin => call(Builtin::Cos, u),
+                    Builtin::Cos => negate(call(Builtin::Sin, u)),
+                    Builtin::Tan => divide(Self::Number(1.), square(call(Builtin::Cos, u))),
+                    Builtin::Asin => divide(
+                        Self::Number(1.),
+                        call(Builtin::Sqrt, subtract(Self::Number(1.), square(u))),
+                    ),
+                    Builtin::Acos => negate(divide(
+                        Self::Number(1.),
+                        call(Builtin::Sqrt, subtract(Self::Number(1.), square(u))),
+                    )),
+                    Builtin::Atan => divide(Self::Number(1.), add(Self::Number(1.), square(u))),
+                    Builtin::Sinh => call(Builtin::Cosh, u),
+                    Builtin::Cosh => call(Builtin::Sinh, u),
+                    Builtin::Tanh => divide(Self::Number(1.), square(call(Builtin::Cosh, u))),
+                    Builtin::Exp => call(Builtin::Exp, u),
+                    Builtin::Ln => divide(Self::Number(1.), u),
+                    Builtin::Log => {
+                        let base = args.get(1).cloned().unwrap_or(Self::Number(10.));
+                        let quotient = divide(call(Builtin::Ln, u), call(Builtin::Ln, base));
+                        return quotient.derivative_at_depth(variable, depth_left - 1);
+                    }
+                    Builtin::Sqrt => divide(
+                        Self::Number(1.),
+                        multiply(Self::Number(2.), call(Builtin::Sqrt, u)),
+                    ),
+                    Builtin::Abs => divide(u.clone(), call(Builtin::Abs, u)),
+                    // Steps are flat everywhere the derivative exists.
+                    Builtin::Floor | Builtin::Ceil | Builtin::Round => return Ok(Self::Number(0.)),
+                    Builtin::Min | Builtin::Max => {
+                        return Err(EvalError::NotDifferentiable(name.clone()))
+                    }
+                };
+                multiply(outer, d(&args[0])?)
+            }
+        })
+    }
+}
+
+fn call(builtin: Builtin, arg: Expr) -> Expr {
+    Expr::Call(builtin.to_string(), vec![arg])
+}
+
+fn is_number(expr: &Expr, number: f64) -> bool {
+    matches!(expr, Expr::Number(num) if *num == number)
+}
+
+fn negate(operand: Expr) -> Expr {
+    match operand {
+        Expr::Number(num) => Expr::Number(-num),
+        Expr::Unary(UnaryOp::Negate, operand) => *operand,
+        operand => Expr::unary(UnaryOp::Negate, operand),
+    }
+}
+
+fn add(lhs: Expr, rhs: Expr) -> Expr {
+    match (lhs, rhs) {
+        (Expr::Number(lhs), Expr::Number(rhs)) => Expr::Number(lhs + rhs),
+        (lhs, rhs) if is_number(&lhs, 0.) => rhs,
+        (lhs, rhs) if is_number(&rhs, 0.) => lhs,
+        (lhs, rhs) => Expr::binary(BinaryOp::Add, lhs, rhs),
+    }
+}
+
+fn subtract(lhs: Expr, rhs: Expr) -> Expr {
+    match (lhs, rhs) {
+        (Expr::Number(lhs), Expr::Number(rhs)) => Expr::Number(lhs - rhs),
+        (lhs, rhs) if is_number(&lhs, 0.) => negate(rhs),
+        (lhs, rhs) if is_number(&rhs, 0.) => lhs,
+        (lhs, rhs) => Expr::binary(BinaryOp::Subtract, lhs, rhs),
+    }
+}
+
+fn multiply(lhs: Expr, rhs: Expr) -> Expr {
+    match (lhs, rhs) {
+        (Expr::Number(lhs), Expr::Number(rhs)) => Expr::Number(lhs * rhs),
+        (lhs, rhs) if is_number(&lhs, 0.) || is_number(&rhs, 0.) => Expr::Number(0.),
+        (lhs, rhs) if is_number(&lhs, 1.) => rhs,
+        (lhs, rhs) if is_number(&rhs, 1.) => lhs,
+        (lhs, rhs) => Expr::binary(BinaryOp::Multiply, lhs, rhs),
+    }
+}
+
+fn divide(lhs: Expr, rhs: Expr) -> Expr {
+    match (lhs, rhs) {
+        (lhs, _) if is_number(&lhs, 0.) => Expr::Number(0.),
+        (lhs, rhs) if is_number(&rhs, 1.) => lhs,
+        (lhs, rhs) => Expr::binary(BinaryOp::Divide, lhs, rhs),
+    }
+}
+
+fn pow(base: Expr, exponent: Expr) -> Expr {
+    match (base, exponent) {
+        (_, exponent) if is_number(&exponent, 0.) => Expr::Number(1.),
+        (base, exponent) if is_number(&exponent, 1.) => base,
+        (base, exponent) => Expr::binary(BinaryOp::Pow, base, exponent),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::{Calculation, Definition, EvalError, Function, Limits, ParseError};
+    use std::collections::HashMap;
+
+    fn derivative(input: &str) -> Result<String, EvalError> {
+        let function = Function::try_from(input).unwrap();
+        Ok(function.expr().derivative("x")?.canonical().to_string())
+    }
+
+    #[test]
+    fn differentiation_rules() {
+        assert_eq!(derivative("f(x)=x^2"), Ok("2x".to_string()));
+        assert_eq!(derivative("f(x)=3x+1"), Ok("3".to_string()));
+        assert_eq!(derivative("f(x)=sin(2x)"), Ok("2cos(2x)".to_string()));
+        assert_eq!(derivative("f(x)=1/x"), Ok("-1/x^2".to_string()));
+        assert_eq!(derivative("f(x)=2^x"), Ok("2^x*ln(2)".to_string()));
+        assert_eq!(derivative("f(x)=e^x"), Ok("e^x".to_string()));
+        assert_eq!(derivative("f(x)=e^(2x)"), Ok("2e^(2x)".to_string()));
+        assert_eq!(derivative("f(x)=floor(x)"), Ok("0".to_string()));
+        assert_eq!(
+            derivative("f(x)=max(x,1)"),
+            Err(EvalError::NotDifferentiable("max".to_string()))
+        );
+
+        // Check the rules numerically against the slope between two close points.
+        for input in [
+            "f(x)=x^x",
+            "f(x)=tan(x)/x",
+            "f(x)=asin(x/2)+acos(x/3)+atan(x)",
+            "f(x)=sinh(x)cosh(x)tanh(x)",
+            "f(x)=exp(-x^2)+ln(x)+log(x)+log(x,2)",
+            "f(x)=sqrt(x)abs(x-1)",
+        ] {
+            let function = Function::try_from(input).unwrap();
+            let derivative = function.expr().derivative("x").unwrap();
+            let at = |x: f64| HashMap::from([("x".to_string(), x)]);
+            let y = |x: f64| function.expr().evaluate(&at(x)).unwrap();
+            for x in [0.3, 0.7, 1.5] {
+                let slope = (y(x + 1e-6) - y(x - 1e-6)) / 2e-6;
+                let exact = derivative.evaluate(&at(x)).unwrap();
+                assert!(
+                    (slope - exact).abs() < 1e-5 * exact.abs().max(1.),
+                    "{input} at {x}: {exact} should be close to {slope}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn derivative_syntax() {
+        let parsed = Definition::parse_all(
+            &[
+                "g(x)=f'(x)",
+                "f(x)=x^3",
+                "f''(x)",
+                "h(x)=d/dx(sin(x))",
+                "k(x)=diff(f(x)^2,x)",
+                "f'(2)",
+                "p(x)=diff(x,2)",
+                "q(x)=sin'(x)",
+                "r(x)=diff(max(x,1),x)",
+            ],
+            &Limits::default(),
+        );
+        let function = |i: usize| match &parsed[i] {
+            Ok(Definition::Function(function)) => function,
+            other => panic!("Expected a function, got {other:?}"),
+        };
+        let at = |i: usize, x: f64| function(i).y_pos(&HashMap::from([("x".to_string(), x)]));
+        assert_eq!(at(0, 2.), Ok(12.));
+        assert_eq!(
+            function(0)
+                .expanded()
+                .map(|expanded| expanded.canonical().to_string()),
+            Some("3x^2".to_string())
+        );
+        assert_eq!(function(1).expanded(), None);
+        assert_eq!(function(2).name, "i");
+        assert_eq!(at(2, 2.), Ok(12.));
+        assert_eq!(at(3, 0.), Ok(1.));
+        assert_eq!(at(4, 1.), Ok(6.));
+        assert_eq!(
+            parsed[5],
+            Ok(Definition::Calculation(Calculation { value: Ok(12.) }))
+        );
+        assert_eq!(parsed[6], Err(ParseError::InvalidTokenPosition(12..13)));
+        assert_eq!(parsed[7], Err(ParseError::InvalidTokenPosition(8..9)));
+        assert_eq!(
+            at(8, 0.),
+            Err(EvalError::NotDifferentiable("max".to_string()))
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use crate::parse::builtins::{Builtin, Constant};
+use crate::parse::limits::Limits;
+use crate::parse::math_functions::EvalError;
+use crate::parse::token::Token;
+use std::collections::HashMap;
+use std::fmt;
+
+/// An operator taking a single operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    /// `-a`
+    Negate,
+}
+
+/// An operator taking a left and a right operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    /// `a+b`
+    Add,
+    /// `a-b`
+    Subtract,
+    /// `a*b`, also used for implicit multiplication like `2x`
+    Multiply,
+    /// `a/b`
+    Divide,
+    /// `a^b`
+    Pow,
+}
+
+impl BinaryOp {
+    /// Applies the operator to two already calculated operands.
+    pub fn apply(self, lhs: f64, rhs: f64) -> f64 {
+        match self {
+            Self::Add => lhs + rhs,
+            Self::Subtract => lhs - rhs,
+            Self::Multiply => lhs * rhs,
+            Self::Divide => lhs / rhs,
+            Self::Pow => lhs.powf(rhs),
+        }
+    }
+
+    /// Applies the operator to two already calculated operands,
+    /// failing if the result is undefined or too large.
+    pub fn checked_apply(self, lhs: f64, rhs: f64) -> Result<f64, EvalError> {
+        match self {
+            Self::Divide if rhs == 0. => Err(EvalError::DivisionByZero),
+            Self::Pow if lhs == 0. && rhs < 0. => Err(EvalError::DivisionByZero),
+            _ => EvalError::check(self, self.apply(lhs, rhs), &[lhs, rhs]),
+        }
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Negate => write!(f, "-"),
+        }
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Token::from(*self))
+    }
+}
+
+/// A parsed mathematical expression.
+///
+/// Every operation is explicit in the tree, so `2x^2` is represented as `Multiply(2, Pow(x, 2))`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    /// A named constant like `pi`, kept by name so it can be printed as written.
+    Constant(Constant),
+    Variable(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// A call to a named function with its argument expressions.
+    Call(String, Vec<Expr>),
+}
+
+impl Expr {
+    pub fn unary(op: UnaryOp, operand: Expr) -> Self {
+        Self::Unary(op, Box::new(operand))
+    }
+
+    pub fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Self {
+        Self::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    /// The direct sub-expressions of this expression, from left to right.
+    pub fn children(&self) -> Vec<&Expr> {
+        match self {
+            Self::Number(_) | Self::Constant(_) | Self::Variable(_) => Vec::new(),
+            Self::Unary(_, operand) => vec![operand],
+            Self::Binary(_, lhs, rhs) => vec![lhs, rhs],
+            Self::Call(_, args) => args.iter().collect(),
+        }
+    }
+
+    /// Visits this expression and every sub-expression, parents before their children.
+    pub fn walk<'a>(&'a self, visit: &mut impl FnMut(&'a Expr)) {
+        visit(self);
+        for child in self.children() {
+            child.walk(visit);
+        }
+    }
+
+    /// The names of all variables used in the expression, in order of first use.
+    pub fn variables(&self) -> Vec<&str> {
+        let mut variables = Vec::new();
+        self.walk(&mut |expr| {
+            if let Expr::Variable(var_name) = expr {
+                if !variables.contains(&var_name.as_str()) {
+                    variables.push(var_name.as_str());
+                }
+            }
+        });
+        variables
+    }
+
+    /// Rebuilds the expression bottom-up, replacing every node with the result of `rewrite`.
+    ///
+    /// Children are rewritten before their parent, so `rewrite` always sees already rewritten operands.
+    pub fn transform(self, rewrite: &mut impl FnMut(Expr) -> Expr) -> Expr {
+        let rebuilt = match self {
+            Self::Number(_) | Self::Constant(_) | Self::Variable(_) => self,
+            Self::Unary(op, operand) => Self::unary(op, operand.transform(rewrite)),
+            Self::Binary(op, lhs, rhs) => {
+                Self::binary(op, lhs.transform(rewrite), rhs.transform(rewrite))
+            }
+            Self::Call(name, args) => Self::Call(
+                name,
+                args.into_iter().map(|arg| arg.transform(rewrite)).collect(),
+            ),
+        };
+        rewrite(rebuilt)
+    }
+
+    /// Calculates the value of the expression.
+    ///
+    /// # Arguments
+    ///
+    /// * `variables` - A map of the expression's variables to their values.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<f64, EvalError>` - On success, the function returns `Ok(f64)`. On failure, it returns `Err(EvalError)`.
+    pub fn evaluate(&self, variables: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        self.evaluate_with_limits(variables, &Limits::default())
+    }
+
+    /// [`Expr::evaluate`], which fails with [`EvalError::RecursionLimit`] if the expression
+    /// is nested deeper than `limits` allow.
+    pub fn evaluate_with_limits(
+        &self,
+        variables: &HashMap<String, f64>,
+        limits: &Limits,
+    ) -> Result<f64, EvalError> {
+        self.evaluate_at_depth(variables, limits.max_evaluation_depth)
+    }
+
+    /// The value, with `depth_left` levels of nesting left before it is too deep to calculate.
+    fn evaluate_at_depth(
+        &self,
+        variables: &HashMap<String, f64>,
+        depth_left: usize,
+    ) -> Result<f64, EvalError> {
+        if depth_left == 0 {
+            return Err(EvalError::RecursionLimit);
+        }
+        let depth_left = depth_left - 1;
+        match self {
+            Self::Number(num) => Ok(*num),
+            Self::Constant(constant) => Ok(constant.value()),
+            Self::Variable(var_name) => variables
+                .get(var_name)
+                .copied()
+                .ok_or_else(|| EvalError::UnknownVariable(var_name.clone())),
+            Self::Unary(UnaryOp::Negate, operand) => {
+                Ok(-operand.evaluate_at_depth(variables, depth_left)?)
+            }
+            Self::Binary(op, lhs, rhs) => {
+                let lhs = lhs.evaluate_at_depth(variables, depth_left)?;
+                let rhs = rhs.evaluate_at_depth(variables, depth_left)?;
+                op.checked_apply(lhs, rhs)
+            }
+            Self::Call(name, args) => {
+                let builtin = Builtin::from_name(name)
+                    .filter(|builtin| builtin.arity().contains(&args.len()))
+                    .ok_or_else(|| EvalError::UnknownFunction(name.clone(), args.len()))?;
+                let args = args
+                    .iter()
+                    .map(|arg| arg.evaluate_at_depth(variables, depth_left))
+                    .collect::<Result<Vec<_>, _>>()?;
+                builtin.checked_apply(&args)
+            }
+        }
+    }
+}
+
+/// Writes the expression with every operation wrapped in parentheses, like `((2*x)+1)`.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(num) => write!(f, "{num}"),
+            Self::Constant(constant) => write!(f, "{constant}"),
+            Self::Variable(var_name) => write!(f, "{var_name}"),
+            Self::Unary(op, operand) => write!(f, "({op}{operand})"),
+            Self::Binary(op, lhs, rhs) => write!(f, "({lhs}{op}{rhs})"),
+            Self::Call(name, args) => {
+                write!(f, "{name}(")?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{arg}")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::{BinaryOp, Expr, Function};
+    use std::collections::HashMap;
+
+    #[test]
+    fn parsed_tree() {
+        let func = Function::try_from("f(x)=2x^2+1").unwrap();
+        assert_eq!(
+            func.expr(),
+            &Expr::binary(
+                BinaryOp::Add,
+                Expr::binary(
+                    BinaryOp::Multiply,
+                    Expr::Number(2.),
+                    Expr::binary(
+                        BinaryOp::Pow,
+                        Expr::Variable("x".to_string()),
+                        Expr::Number(2.)
+                    )
+                ),
+                Expr::Number(1.)
+            )
+        );
+    }
+
+    #[test]
+    fn transform_and_evaluate() {
+        let func = Function::try_from("f(x)=3x+x").unwrap();
+        let mut variables = Vec::new();
+        func.expr().walk(&mut |expr| {
+            if let Expr::Variable(var_name) = expr {
+                variables.push(var_name.clone())
+            }
+        });
+        assert_eq!(variables, ["x", "x"]);
+
+        let substituted = func.expr().clone().transform(&mut |expr| match expr {
+            Expr::Variable(_) => Expr::Number(2.),
+            other => other,
+        });
+        assert_eq!(substituted.to_string(), "((3*2)+2)");
+        assert_eq!(substituted.evaluate(&HashMap::new()), Ok(8.));
+    }
+}
@@ -0,0 +1,25 @@
+/// Bounds on how large an expression may get, so that huge or deeply nested input
+/// fails with an error instead of overflowing the stack or freezing the app.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// How deeply parentheses, operators and calls may be nested.
+    pub max_depth: usize,
+    /// How many numbers, variables, operators and calls an expression may consist of.
+    pub max_nodes: usize,
+    /// How many steps calculating an expression once may take.
+    pub max_steps: usize,
+    /// How deeply an expression may be nested when it is calculated, simplified or differentiated,
+    /// which can be more than `max_depth` once the functions it calls are inlined.
+    pub max_evaluation_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_depth: 200,
+            max_nodes: 2000,
+            max_steps: 100_000,
+            max_evaluation_depth: 1000,
+        }
+    }
+}
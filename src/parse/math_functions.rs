@@ -1,35 +1,203 @@
-use crate::parse::TokenQueue;
-use egui_plot::PlotPoints;
+use crate::parse::compile::{expand_derivatives, Column};
+use crate::parse::derivative::is_derivative;
+use crate::parse::sampler::{
+    sample_adaptive, AdaptiveSampler, SampleIter, SampleOptions, SampledCurve, Viewport,
+};
+use crate::parse::{Expr, Limits, Program, Scope, TokenQueue};
 use itertools::Itertools;
 use regex::Regex;
 use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::sync::LazyLock;
 
-#[derive(thiserror::Error, Debug, PartialEq)]
+static FUNCTION_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^\s*(?<FunctionName>\w+)\s*\(\s*(?<FunctionVariables>[a-z]+(?:\s*,\s*[a-z]+)*)\s*\)\s*=\s*(?<Expression>[a-z01-9^*/()+\-.,'\s]*[a-z01-9^*/()+\-.,'])\s*$").expect("Regex should compile")
+});
+
+/// Loosely matches anything that looks like an attempt at defining a function.
+static IS_FUNCTION_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"^\w+\((?:\d+[a-z]*|\d*[a-z]+)+\)=(?:\(?(?:\d+[a-z]*|\d*[a-z]+)[+\-^/*)]?)+$"#)
+        .expect("Regex compiles")
+});
+
+/// A byte range into the parsed input.
+pub type Span = Range<usize>;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
 pub enum ParseError {
-    #[error("All variables defined in the function are not used.")]
-    VariableDefinitionAndUseMismatch,
+    #[error("Variables declared but never used: {}", .0.join(", "))]
+    VariableDefinitionAndUseMismatch(Vec<String>),
     #[error("Unknown variable in expression: \"{0}\"")]
-    UnknownVariable(String),
+    UnknownVariable(String, Span),
     #[error("Unable to parse")]
-    UnableToParse,
+    UnableToParse(Span),
     #[error("No function defined")]
     NoFunctionDefined,
     #[error("Unable to find required argument: {0} in input")]
     UnableToFind(String),
     #[error("Token in invalid position")]
-    InvalidTokenPosition,
+    InvalidTokenPosition(Span),
     #[error("Two decimal points used in the same number!")]
-    DoubleDecimal,
+    DoubleDecimal(Span),
     #[error("Unclosed parenthesis")]
-    UnclosedParenthesis,
+    UnclosedParenthesis(Span),
+    #[error("Wrong number of arguments ({1}) given to \"{0}\"")]
+    WrongArgumentCount(String, usize, Span),
+    #[error("Expression is nested more than {0} levels deep")]
+    TooDeeplyNested(usize, Span),
+    #[error("Expression has more than {0} parts")]
+    TooLarge(usize, Span),
+    #[error("Definition depends on itself: {}", .0.join(" → "))]
+    CircularDefinition(Vec<String>),
+    #[error("Value can't be calculated: {0}")]
+    InvalidValue(EvalError, Span),
+    #[error("\"{0}\" is already a built-in function or constant")]
+    ReservedName(String, Span),
+}
+
+impl ParseError {
+    /// The part of the input the error was found in, if it can be pinpointed.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Self::UnknownVariable(_, span)
+            | Self::UnableToParse(span)
+            | Self::InvalidTokenPosition(span)
+            | Self::DoubleDecimal(span)
+            | Self::UnclosedParenthesis(span)
+            | Self::WrongArgumentCount(_, _, span)
+            | Self::TooDeeplyNested(_, span)
+            | Self::TooLarge(_, span)
+            | Self::InvalidValue(_, span)
+            | Self::ReservedName(_, span) => Some(span.clone()),
+            Self::VariableDefinitionAndUseMismatch(_)
+            | Self::NoFunctionDefined
+            | Self::UnableToFind(_)
+            | Self::CircularDefinition(_) => None,
+        }
+    }
+
+    /// Moves the span of the error `by` bytes to the right,
+    /// used when the error was found in a part of a larger input.
+    pub(crate) fn offset(mut self, by: usize) -> Self {
+        match &mut self {
+            Self::UnknownVariable(_, span)
+            | Self::UnableToParse(span)
+            | Self::InvalidTokenPosition(span)
+            | Self::DoubleDecimal(span)
+            | Self::UnclosedParenthesis(span)
+            | Self::WrongArgumentCount(_, _, span)
+            | Self::TooDeeplyNested(_, span)
+            | Self::TooLarge(_, span)
+            | Self::InvalidValue(_, span)
+            | Self::ReservedName(_, span) => *span = span.start + by..span.end + by,
+            Self::VariableDefinitionAndUseMismatch(_)
+            | Self::NoFunctionDefined
+            | Self::UnableToFind(_)
+            | Self::CircularDefinition(_) => (),
+        }
+        self
+    }
+
+    /// Formats the error together with the input it was found in, pointing out the span with carets:
+    ///
+    /// ```text
+    /// f(x)=2x+)
+    ///         ^
+    /// Token in invalid position
+    /// ```
+    pub fn diagnostic<'a>(&'a self, input: &'a str) -> Diagnostic<'a> {
+        Diagnostic { error: self, input }
+    }
+}
+
+/// A [`ParseError`] displayed together with its input, see [`ParseError::diagnostic`].
+pub struct Diagnostic<'a> {
+    error: &'a ParseError,
+    input: &'a str,
+}
+
+impl fmt::Display for Diagnostic<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.input)?;
+        let parts = self
+            .error
+            .span()
+            .and_then(|span| Some((self.input.get(..span.start)?, self.input.get(span)?)));
+        if let Some((before, inside)) = parts {
+            writeln!(
+                f,
+                "{}{}",
+                " ".repeat(before.chars().count()),
+                "^".repeat(inside.chars().count().max(1))
+            )?;
+        }
+        write!(f, "{}", self.error)
+    }
+}
+/// An error from calculating the value of an already parsed expression.
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum EvalError {
+    #[error("No value given for variable \"{0}\"")]
+    UnknownVariable(String),
+    #[error("Unknown function \"{0}\" taking {1} arguments")]
+    UnknownFunction(String, usize),
+    #[error("\"{0}\" is undefined for {1}")]
+    DomainError(String, f64),
+    #[error("Division by zero")]
+    DivisionByZero,
+    #[error("Result is too large")]
+    Overflow,
+    #[error("Expression is nested too deeply to calculate")]
+    RecursionLimit,
+    #[error("Calculation takes more than {0} steps")]
+    StepLimit(usize),
+    #[error("\"{0}\" calls itself")]
+    RecursiveCall(String),
+    #[error("\"{0}\" can't be used because it has an error")]
+    DependencyError(String),
+    #[error("\"{0}\" can't be differentiated")]
+    NotDifferentiable(String),
+    #[error("Expected a column of values for each of the {0} variables, got {1}")]
+    WrongColumnCount(usize, usize),
+    #[error("Expected every column to have {0} values, as many as the output")]
+    WrongColumnLength(usize),
+}
+
+impl EvalError {
+    /// Whether the error only means the value is undefined at the given point,
+    /// like `1/x` at `x=0`, rather than the expression never being possible to calculate.
+    pub fn is_pointwise(&self) -> bool {
+        matches!(
+            self,
+            Self::DomainError(..) | Self::DivisionByZero | Self::Overflow
+        )
+    }
+
+    /// Checks the `result` of applying the operation `name` to finite `args`,
+    /// turning NaN into a domain error and infinities into an overflow.
+    pub(crate) fn check(name: impl fmt::Display, result: f64, args: &[f64]) -> Result<f64, Self> {
+        if !args.iter().all(|arg| arg.is_finite()) {
+            Ok(result)
+        } else if result.is_nan() {
+            Err(Self::DomainError(name.to_string(), args[0]))
+        } else if result.is_infinite() {
+            Err(Self::Overflow)
+        } else {
+            Ok(result)
+        }
+    }
 }
+
 #[derive(Debug, PartialEq)]
 pub struct Function {
     pub name: String,
-    x_value: f64,
-    internal_offset: f64,
     tokens: TokenQueue,
     variables: Vec<String>,
+    /// The body compiled for fast calculation, or the error calculating it would always fail with.
+    program: Result<Program, EvalError>,
+    /// The body with its derivatives worked out, if it has any and they can be.
+    expanded: Option<Expr>,
 }
 
 impl TryFrom<String> for Function {
@@ -46,6 +214,32 @@ impl TryFrom<&str> for Function {
     }
 }
 
+/// Values for the variables of a function other than the first, which is plotted along the x-axis.
+///
+/// Made by [`Function::bind`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Binding {
+    /// The values of the variables after the first, in declaration order.
+    values: Vec<f64>,
+}
+
+impl Binding {
+    /// The values of every variable in declaration order, with the plotted one set to `x`.
+    pub fn at(&self, x: f64) -> Vec<f64> {
+        std::iter::once(x)
+            .chain(self.values.iter().copied())
+            .collect()
+    }
+
+    /// Calculates `program` for every x-value in `xs` at once, see [`Program::evaluate_batch`].
+    pub(crate) fn evaluate_batch(&self, program: &Program, xs: &[f64], output: &mut [f64]) {
+        let columns = std::iter::once(Column::Values(xs))
+            .chain(self.values.iter().map(|&value| Column::Constant(value)))
+            .collect::<Vec<_>>();
+        program.evaluate_columns(&columns, output);
+    }
+}
+
 impl Function {
     /// Calculates the y-value of the function at the current x-value.
     ///
@@ -55,35 +249,190 @@ impl Function {
     ///
     /// # Returns
     ///
-    /// * `Result<f64, ParseError>` - On success, the function returns `Ok(f64)`. On failure, it returns `Err(ParseError)`.
-    pub fn y_pos(&self, variables: &HashMap<String, f64>) -> Result<f64, ParseError> {
-        self.tokens.calculate(variables)
+    /// * `Result<f64, EvalError>` - On success, the function returns `Ok(f64)`. On failure, it returns `Err(EvalError)`.
+    pub fn y_pos(&self, variables: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        let program = self.program()?;
+        let values = program
+            .slots()
+            .iter()
+            .map(|var| {
+                variables
+                    .get(var)
+                    .copied()
+                    .ok_or_else(|| EvalError::UnknownVariable(var.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        program.evaluate(&values)
+    }
+
+    /// Calculates the function for many values of its variables at once, writing the y-values to `output`.
+    ///
+    /// `columns` has the values of every declared variable in declaration order, each as long as `output`.
+    /// Points where the function is undefined are NaN, see [`Program::evaluate_batch`].
+    ///
+    /// # Errors
+    ///
+    /// The error calculating the function always fails with,
+    /// or [`EvalError::WrongColumnCount`] and [`EvalError::WrongColumnLength`] if `columns` don't fit the function.
+    pub fn y_batch(&self, columns: &[&[f64]], output: &mut [f64]) -> Result<(), EvalError> {
+        let program = self.program()?;
+        if columns.len() != program.slots().len() {
+            return Err(EvalError::WrongColumnCount(
+                program.slots().len(),
+                columns.len(),
+            ));
+        }
+        if columns.iter().any(|column| column.len() != output.len()) {
+            return Err(EvalError::WrongColumnLength(output.len()));
+        }
+        program.evaluate_batch(columns, output);
+        Ok(())
+    }
+
+    /// The function's body compiled for fast calculation,
+    /// with a slot for every declared variable in declaration order.
+    pub fn program(&self) -> Result<&Program, EvalError> {
+        self.program.as_ref().map_err(Clone::clone)
+    }
+
+    /// The parsed expression tree of the function's body.
+    pub fn expr(&self) -> &Expr {
+        self.tokens.expr()
     }
 
-    fn current_point(&self, variables: &HashMap<String, f64>) -> Result<[f64; 2], ParseError> {
-        Ok([self.x_value, self.y_pos(variables)?])
+    /// The body with every derivative in it worked out and simplified, like `2x` for `diff(x^2,x)`,
+    /// or `None` if it has no derivatives or they can't be calculated.
+    pub fn expanded(&self) -> Option<&Expr> {
+        self.expanded.as_ref()
     }
 
-    /// Generates a naive map of the function's variables to the x-value.
-    pub fn generate_naive_map(&self) -> HashMap<String, f64> {
-        let mut map = HashMap::new();
-        for var in &self.variables {
-            map.insert(var.clone(), self.x_value);
+    /// The variables the function is declared with, in declaration order.
+    pub fn variables(&self) -> &[String] {
+        &self.variables
+    }
+
+    /// The names of the functions called in the body, built-in ones included, in order of first use.
+    ///
+    /// Derivatives of functions, like `f'(x)`, count as calls to the function itself.
+    pub fn calls(&self) -> Vec<&str> {
+        let mut calls = Vec::new();
+        self.expr().walk(&mut |expr| {
+            if let Expr::Call(name, _) = expr {
+                let name = name.trim_end_matches('\'');
+                if !calls.contains(&name) {
+                    calls.push(name);
+                }
+            }
+        });
+        calls
+    }
+
+    /// The declared variables that are never used in the function's body, in declaration order.
+    pub fn unused_variables(&self) -> Vec<&str> {
+        let used = self.expr().variables();
+        self.variables
+            .iter()
+            .map(String::as_str)
+            .filter(|var| !used.contains(var))
+            .collect()
+    }
+
+    /// A problem with the function that doesn't stop it from being calculated,
+    /// like declared variables that are never used.
+    pub fn warning(&self) -> Option<ParseError> {
+        let unused = self.unused_variables();
+        if unused.is_empty() {
+            None
+        } else {
+            Some(ParseError::VariableDefinitionAndUseMismatch(
+                unused.into_iter().map(String::from).collect(),
+            ))
         }
-        map
     }
 
+    /// The variable plotted along the x-axis, which is the first one declared.
+    pub fn axis(&self) -> Option<&str> {
+        self.variables.first().map(String::as_str)
+    }
+
+    /// The declared variables other than the plotted one, which are given values by [`Function::bind`].
+    pub fn arguments(&self) -> &[String] {
+        self.variables.get(1..).unwrap_or_default()
+    }
+
+    /// Fixes every variable but the plotted one to its value in `arguments`, so the function can be plotted.
+    ///
+    /// Values for names that aren't [arguments](Function::arguments) are ignored.
+    ///
+    /// # Errors
+    ///
+    /// [`EvalError::UnknownVariable`] if an argument has no value.
+    pub fn bind(&self, arguments: &HashMap<String, f64>) -> Result<Binding, EvalError> {
+        let values = self
+            .arguments()
+            .iter()
+            .map(|name| {
+                arguments
+                    .get(name)
+                    .copied()
+                    .ok_or_else(|| EvalError::UnknownVariable(name.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Binding { values })
+    }
+
+    /// Parses a function, requiring every declared variable to be used.
     fn parse(input: &str) -> Result<Self, ParseError> {
-        let function_match = Regex::new(r"^(?<FunctionName>\w+)\((?<FunctionVariables>(?:[a-z]+,?)+)\)=(?<Expression>[a-z01-9^*/()+\-.]+)$").expect("Regex should compile");
-        let is_function_regex = Regex::new(
-            r#"^[a-z]+\((?:\d+[a-z]*|\d*[a-z]+)+\)=(?:\(?(?:\d+[a-z]*|\d*[a-z]+)[+\-^/*)]?)+$"#,
-        )
-        .expect("Regex compiles");
-        let captures = function_match.captures(input).ok_or({
-            if !is_function_regex.is_match(input) {
+        let function = Self::parse_lenient(input)?;
+        match function.warning() {
+            Some(warning) => Err(warning),
+            None => Ok(function),
+        }
+    }
+
+    /// Parses a function like [`Function::try_from`], but allows declared variables to go unused.
+    ///
+    /// Unused variables are instead reported by [`Function::warning`].
+    pub fn parse_lenient(input: &str) -> Result<Self, ParseError> {
+        Self::parse_lenient_with_limits(input, &Limits::default())
+    }
+
+    /// Parses a function like [`Function::parse_lenient`], failing if it is larger than `limits` allow.
+    ///
+    /// If calculating the function would take more steps than allowed,
+    /// it fails with [`EvalError::StepLimit`] when calculated instead.
+    pub fn parse_lenient_with_limits(input: &str, limits: &Limits) -> Result<Self, ParseError> {
+        Self::parse_in(input, &Scope::new(), limits)
+    }
+
+    /// The name of the function `input` defines and the number of variables it takes,
+    /// found without parsing its body.
+    pub(crate) fn declaration(input: &str) -> Option<(&str, usize)> {
+        let captures = FUNCTION_REGEX.captures(input)?;
+        Some((
+            captures.name("FunctionName")?.as_str(),
+            captures
+                .name("FunctionVariables")?
+                .as_str()
+                .split(',')
+                .count(),
+        ))
+    }
+
+    /// Parses a function like [`Function::parse_lenient_with_limits`], whose body may call the functions
+    /// and use the parameters in `scope`, like `h(x)=f(g(x))` or `f(x)=a*x^2`.
+    ///
+    /// Parameters are compiled in with the value they have in `scope`.
+    ///
+    /// The called functions are compiled into this one, so it has to be parsed again when they change.
+    /// A call back to this function, directly or through others, fails with [`EvalError::RecursiveCall`]
+    /// when it is calculated.
+    pub fn parse_in(input: &str, scope: &Scope, limits: &Limits) -> Result<Self, ParseError> {
+        let captures = FUNCTION_REGEX.captures(input).ok_or_else(|| {
+            if !IS_FUNCTION_REGEX.is_match(&input.split_whitespace().collect::<String>()) {
                 ParseError::NoFunctionDefined
             } else {
-                ParseError::UnableToParse
+                ParseError::UnableToParse(0..input.len())
             }
         })?;
 
@@ -96,85 +445,282 @@ impl Function {
             .ok_or(ParseError::UnableToFind("function variables".to_string()))?
             .as_str()
             .split(',')
-            .map(String::from)
+            .map(|variable| variable.trim().to_string())
             .collect_vec();
 
         let ex = captures
             .name("Expression")
-            .ok_or(ParseError::UnableToFind("function expression".to_string()))?
-            .as_str()
-            .to_string();
-        let test_ex = TokenQueue::new(&ex, &function_variables)?;
+            .ok_or(ParseError::UnableToFind("function expression".to_string()))?;
+        Self::from_body(
+            function_name,
+            function_variables,
+            ex.as_str(),
+            ex.start(),
+            scope,
+            limits,
+        )
+    }
+
+    /// Parses the function called `name` of `variables`, whose body `body` starts at byte `body_start` of the input,
+    /// like [`Function::parse_in`] does once it has found the parts of the definition.
+    pub(crate) fn from_body(
+        name: &str,
+        variables: Vec<String>,
+        body: &str,
+        body_start: usize,
+        scope: &Scope,
+        limits: &Limits,
+    ) -> Result<Self, ParseError> {
+        let body_span = body_start..body_start + body.len();
+        let test_ex = TokenQueue::in_scope(body, &variables, scope, limits)
+            .map_err(|e| e.offset(body_start))?;
 
+        // The tokenizer only produces declared variables, but check the finished tree as well
+        // so that nothing can slip through to evaluation.
+        if let Some(undeclared) = test_ex.expr().variables().into_iter().find(|var| {
+            !variables.iter().any(|declared| declared == var) && scope.parameter(var).is_none()
+        }) {
+            return Err(ParseError::UnknownVariable(
+                undeclared.to_string(),
+                body_span,
+            ));
+        }
+
+        let mut has_derivatives = false;
+        test_ex.expr().walk(&mut |expr| {
+            has_derivatives |= matches!(expr, Expr::Call(name, _) if is_derivative(name));
+        });
         Ok(Function {
-            name: function_name.to_string(),
-            x_value: 0.0,
-            internal_offset: 0.0,
+            name: name.to_string(),
+            program: Program::compile_function(
+                Some(name),
+                test_ex.expr(),
+                &variables,
+                scope,
+                limits,
+            ),
+            expanded: has_derivatives
+                .then(|| {
+                    expand_derivatives(Some(name), test_ex.expr(), scope, limits)
+                        .ok()
+                        .map(|expanded| expanded.simplified_with_limits(limits))
+                })
+                .flatten(),
             tokens: test_ex,
-            variables: function_variables,
+            variables,
         })
     }
 
-    /// Generates the points to be plotted for the mathematical function.
+    /// Generates the points to be plotted for the part of the function visible in `viewport`.
+    ///
+    /// The x-range sampled is the visible one plus a margin for panning,
+    /// with the sampling resolution matched to the pixels on screen, see [`Viewport::sample_options`].
+    pub fn plot_points(
+        &self,
+        viewport: &Viewport,
+        options: &SampleOptions,
+        binding: &Binding,
+    ) -> Result<SampledCurve, EvalError> {
+        let mut sampler = self.start_plot(viewport, options);
+        loop {
+            if let Some(curve) = self.continue_plot(&mut sampler, binding, usize::MAX) {
+                return curve;
+            }
+        }
+    }
+
+    /// Starts generating the points to be plotted like [`Function::plot_points`] does,
+    /// but to be calculated a slice at a time with [`Function::continue_plot`].
+    pub(crate) fn start_plot(
+        &self,
+        viewport: &Viewport,
+        options: &SampleOptions,
+    ) -> AdaptiveSampler {
+        let (min_x, max_x) = viewport.sample_x();
+        AdaptiveSampler::new(min_x, max_x, viewport.sample_options(options))
+    }
+
+    /// Calculates up to `budget` more points of a plot started with [`Function::start_plot`],
+    /// giving the curve once it is finished.
+    pub(crate) fn continue_plot(
+        &self,
+        sampler: &mut AdaptiveSampler,
+        binding: &Binding,
+        budget: usize,
+    ) -> Option<Result<SampledCurve, EvalError>> {
+        let program = match self.program() {
+            Ok(program) => program,
+            Err(error) => return Some(Err(error)),
+        };
+        sampler
+            .advance(|xs, ys| binding.evaluate_batch(program, xs, ys), budget)
+            .then(|| Ok(sampler.curve()))
+    }
+
+    /// Generates the points of the function between two x-values.
     ///
     /// # Arguments
     ///
     /// * `min_x` - The minimum x-value for which to calculate the function.
     /// * `max_x` - The maximum x-value for which to calculate the function.
+    /// * `options` - How densely to sample the function, see [`SampleOptions`].
+    /// * `binding` - The values of the other variables, see [`Function::bind`].
     ///
     /// # Returns
     ///
-    /// * `Result<PlotPoints, ParseError>` - On success, the function returns `Ok(PlotPoints)`. On failure, it returns `Err(ParseError)`.
-    pub fn plot_points(&mut self, min_x: f64, max_x: f64) -> Result<PlotPoints, ParseError> {
-        let mut points = Vec::new();
+    /// * `Result<SampledCurve, EvalError>` - On success, the function returns `Ok(SampledCurve)`. On failure, it returns `Err(EvalError)`.
+    ///
+    /// The curve is split up where the function is undefined or jumps, rather than failing the whole plot
+    /// or drawing a line across the gap.
+    pub fn sample_range(
+        &self,
+        min_x: f64,
+        max_x: f64,
+        options: &SampleOptions,
+        binding: &Binding,
+    ) -> Result<SampledCurve, EvalError> {
+        let program = self.program()?;
+        let samples = sample_adaptive(
+            |xs, ys| binding.evaluate_batch(program, xs, ys),
+            min_x,
+            max_x,
+            options,
+        );
+        Ok(SampledCurve::from_samples(&samples, options))
+    }
 
-        self.internal_offset = ((max_x + (max_x / 100.)) - (min_x - (min_x / 100.))) / 2000.;
-        self.x_value = min_x;
-        while self.x_value < max_x {
-            points.push(self.current_point(&self.generate_naive_map())?);
-            // Function is iterator, so self.next() automatically increments x_value
-            self.next();
-        }
-        Ok(PlotPoints::from(points))
+    /// `count` evenly spaced points of the function from `min_x` to `max_x`, both included.
+    ///
+    /// The function is only borrowed, so several ranges can be sampled at the same time.
+    pub fn samples<'a>(
+        &'a self,
+        min_x: f64,
+        max_x: f64,
+        count: usize,
+        binding: &'a Binding,
+    ) -> SampleIter<'a> {
+        SampleIter::new(self, min_x, max_x, count, binding)
+    }
+
+    /// Calculates the y-value with the plotted variable set to `x`, and the others to their value in `binding`.
+    pub(crate) fn y_at(&self, x: f64, binding: &Binding) -> Result<f64, EvalError> {
+        self.program()?.evaluate(&binding.at(x))
     }
 
+    /// The definition of the function with its body simplified, like `f(x)=3x^2` for `f(x)=2x*x+x^2`,
+    /// see [`Expr::simplified`] and [`Expr::canonical`].
     pub fn internal_representation(&self) -> String {
         format!(
             "{}({})={}",
             self.name,
             self.variables.join(","),
-            self.tokens.input_representation
+            self.expr().simplified().canonical()
         )
     }
 }
 
-impl Iterator for Function {
-    type Item = Result<(f64, f64), ParseError>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.x_value += self.internal_offset;
-        match self.y_pos(&self.generate_naive_map()) {
-            Ok(y_pos) => Some(Ok((self.x_value, y_pos))),
-            Err(e) => Some(Err(e)),
-        }
-    }
-}
-
 #[cfg(test)]
 mod test {
     use crate::parse::math_functions::ParseError;
-    use crate::parse::Function;
+    use crate::parse::{Binding, EvalError, Function, Limits, SampleOptions, Scope};
+    use std::collections::HashMap;
+    use std::sync::Arc;
 
     #[test]
     fn parse_function() {
         let test_fn = "f(t,b)=2t+5b";
         assert!(dbg!(Function::try_from(test_fn)).is_ok());
+        assert_eq!(
+            Function::try_from("f(x)=2x*x+x^2")
+                .unwrap()
+                .internal_representation(),
+            "f(x)=3x^2"
+        );
     }
 
     #[test]
     fn math_function_calculate() {
         let input = "f(test)=2test";
-        dbg!(Function::try_from(input));
+        assert!(dbg!(Function::try_from(input)).is_ok());
+    }
+
+    #[test]
+    fn error_span() {
+        let input = "f(x)=2x+)";
+        let error = Function::try_from(input).unwrap_err();
+        assert_eq!(error, ParseError::InvalidTokenPosition(8..9));
+        assert_eq!(
+            error.diagnostic(input).to_string(),
+            "f(x)=2x+)\n        ^\nToken in invalid position"
+        );
+
+        let limits = Limits {
+            max_depth: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            Function::parse_lenient_with_limits("f(x)=((x))", &limits),
+            Err(ParseError::TooDeeplyNested(2, 7..8))
+        );
+    }
+
+    #[test]
+    fn evaluation_errors() {
+        let at = |input: &str, x: f64| {
+            Function::try_from(input)
+                .unwrap()
+                .y_pos(&HashMap::from([("x".to_string(), x)]))
+        };
+        assert_eq!(at("f(x)=1/x", 0.), Err(EvalError::DivisionByZero));
+        assert_eq!(
+            at("f(x)=sqrt(x)", -4.),
+            Err(EvalError::DomainError("sqrt".to_string(), -4.))
+        );
+        assert_eq!(
+            at("f(x)=ln(x)", 0.),
+            Err(EvalError::DomainError("ln".to_string(), 0.))
+        );
+        assert_eq!(at("f(x)=10^x", 400.), Err(EvalError::Overflow));
+        assert_eq!(
+            Function::try_from("f(x)=2x")
+                .unwrap()
+                .y_pos(&HashMap::new()),
+            Err(EvalError::UnknownVariable("x".to_string()))
+        );
+        assert!(Function::try_from("f(x)=1/x")
+            .unwrap()
+            .sample_range(-1., 1., &SampleOptions::default(), &Binding::default())
+            .is_ok());
+    }
+
+    #[test]
+    fn binding_arguments() {
+        let function = Function::try_from("f(t,b)=2t+5b").unwrap();
+        assert_eq!(function.axis(), Some("t"));
+        assert_eq!(function.arguments(), ["b"]);
+        assert_eq!(
+            function.bind(&HashMap::new()),
+            Err(EvalError::UnknownVariable("b".to_string()))
+        );
+        let binding = function
+            .bind(&HashMap::from([
+                ("b".to_string(), 2.),
+                ("t".to_string(), 100.),
+            ]))
+            .unwrap();
+        assert_eq!(binding.at(3.), [3., 2.]);
+        assert_eq!(
+            function.samples(0., 1., 2, &binding).collect::<Vec<_>>(),
+            [Ok((0., 10.)), Ok((1., 12.))]
+        );
+        let curve = function
+            .sample_range(0., 1., &SampleOptions::default(), &binding)
+            .unwrap();
+        assert!(curve
+            .segments
+            .concat()
+            .iter()
+            .all(|[x, y]| *y == 2. * x + 10.));
     }
 
     #[test]
@@ -182,7 +728,58 @@ mod test {
         let test_fn = "f(t,b,c)=2t+5b";
         assert_eq!(
             Function::try_from(test_fn),
-            Err(ParseError::VariableDefinitionAndUseMismatch)
+            Err(ParseError::VariableDefinitionAndUseMismatch(vec![
+                "c".to_string()
+            ]))
+        );
+
+        let lenient = Function::parse_lenient(test_fn).unwrap();
+        assert_eq!(lenient.unused_variables(), ["c"]);
+        assert_eq!(
+            lenient.warning().unwrap().to_string(),
+            "Variables declared but never used: c"
+        );
+
+        assert_eq!(
+            Function::try_from("f(t,b)=2t+5bc"),
+            Err(ParseError::UnknownVariable("c".to_string(), 12..13))
+        );
+    }
+
+    #[test]
+    fn calls_between_functions() {
+        let limits = Limits::default();
+        let mut scope = Scope::new();
+        for input in ["f(x)=x^2", "g(x)=x+1", "k(a,b)=a-b"] {
+            scope.define(Arc::new(
+                Function::parse_in(input, &scope, &limits).unwrap(),
+            ));
+        }
+        let at =
+            |function: &Function, x: f64| function.y_pos(&HashMap::from([("x".to_string(), x)]));
+
+        let h = Function::parse_in("h(x)=f(g(x))", &scope, &limits).unwrap();
+        assert_eq!(at(&h, 2.), Ok(9.));
+        let swapped = Function::parse_in("s(x)=k(1,x)+2f(x)", &scope, &limits).unwrap();
+        assert_eq!(at(&swapped, 3.), Ok(16.));
+        scope.define(Arc::new(h));
+        let nested = Function::parse_in("n(x)=h(h(x))-g(x)", &scope, &limits).unwrap();
+        assert_eq!(at(&nested, 1.), Ok(25. - 2.));
+
+        assert_eq!(
+            Function::parse_in("w(x)=k(x)", &scope, &limits),
+            Err(ParseError::WrongArgumentCount("k".to_string(), 1, 5..9))
+        );
+        assert_eq!(
+            Function::parse_lenient("w(x)=f(x)"),
+            Err(ParseError::UnknownVariable("f".to_string(), 5..6))
+        );
+
+        // `f` calling itself through `h` and `g` must not use the old definition of `f`.
+        let recursive = Function::parse_in("f(x)=h(x)", &scope, &limits).unwrap();
+        assert_eq!(
+            recursive.program(),
+            Err(EvalError::RecursiveCall("f".to_string()))
         );
     }
 }
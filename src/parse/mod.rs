@@ -1,7 +1,37 @@
+mod builtins;
+pub use builtins::{Builtin, Constant};
+
+mod expr;
+pub use expr::{BinaryOp, Expr, UnaryOp};
+
+mod derivative;
+
+mod simplify;
+pub use simplify::Canonical;
+
+mod compile;
+pub use compile::Program;
+
 mod token;
 pub use token::TokenQueue;
 
+mod limits;
+pub use limits::Limits;
+
+mod scope;
+pub use scope::Scope;
+
+mod dependencies;
+pub use dependencies::DependencyGraph;
+
+mod definition;
+pub use definition::{Calculation, Definition, Parameter};
+
 mod math_functions;
-pub use math_functions::{Function, ParseError};
+pub use math_functions::{Binding, Diagnostic, EvalError, Function, ParseError, Span};
+mod sampler;
+#[cfg(target_arch = "wasm32")]
+pub(crate) use sampler::AdaptiveSampler;
+pub use sampler::{SampleIter, SampleOptions, SampledCurve, Viewport};
 #[cfg(test)]
 mod test;
@@ -0,0 +1,682 @@
+use crate::parse::{Binding, EvalError, Function};
+use egui_plot::PlotBounds;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// Segments narrower than this many pixels are never split further,
+/// which stops refinement at jumps the curve never settles across.
+const MIN_SEGMENT_PIXELS: f64 = 0.25;
+
+/// Neighbouring samples this many pixels apart vertically are not joined,
+/// if the sampler could not find anything in between them.
+const JUMP_PIXELS: f64 = 100.;
+
+/// How much of the visible width is also sampled on either side,
+/// so panning a little doesn't need new points or show the curve ending early.
+const OVERSCAN: f64 = 0.25;
+
+/// Distance in pixels between the evenly spaced points sampled before refining.
+const INITIAL_SPACING_PIXELS: f64 = 8.;
+
+/// Settings for how densely a function is sampled for plotting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleOptions {
+    /// Largest allowed distance, in pixels, between the drawn line and the actual curve.
+    pub tolerance: f64,
+    /// Hard limit on how many times the function is calculated.
+    pub max_points: usize,
+    /// Number of evenly spaced points the range is split into before refining.
+    pub initial_points: usize,
+    /// Pixels per unit along the x- and y-axis, used to measure the error on screen.
+    pub scale: [f64; 2],
+    /// Whether to look for vertical asymptotes, see [`SampledCurve::asymptotes`].
+    pub detect_asymptotes: bool,
+}
+
+impl Default for SampleOptions {
+    fn default() -> Self {
+        Self {
+            tolerance: 0.5,
+            max_points: 4000,
+            initial_points: 64,
+            scale: [1., 1.],
+            detect_asymptotes: false,
+        }
+    }
+}
+
+/// The part of the plot that is visible on screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    /// The visible range of values along both axes.
+    pub bounds: PlotBounds,
+    /// Width and height of the plot in pixels.
+    pub pixel_size: [f32; 2],
+}
+
+impl Viewport {
+    pub fn new(bounds: PlotBounds, pixel_size: [f32; 2]) -> Self {
+        Self { bounds, pixel_size }
+    }
+
+    /// The x-range that is visible on screen.
+    pub fn visible_x(&self) -> (f64, f64) {
+        (self.bounds.min()[0], self.bounds.max()[0])
+    }
+
+    /// The x-range to sample, which is the visible range with a margin on either side.
+    pub fn sample_x(&self) -> (f64, f64) {
+        let (min_x, max_x) = self.visible_x();
+        let margin = (max_x - min_x) * OVERSCAN;
+        (min_x - margin, max_x + margin)
+    }
+
+    /// Pixels per unit along the x- and y-axis.
+    pub fn scale(&self) -> [f64; 2] {
+        [
+            self.pixel_size[0] as f64 / self.bounds.width(),
+            self.pixel_size[1] as f64 / self.bounds.height(),
+        ]
+    }
+
+    /// `options` with the scale and initial points matched to the pixels of this viewport.
+    pub fn sample_options(&self, options: &SampleOptions) -> SampleOptions {
+        let sampled_pixels = self.pixel_size[0] as f64 * (1. + 2. * OVERSCAN);
+        SampleOptions {
+            scale: self.scale(),
+            initial_points: (sampled_pixels / INITIAL_SPACING_PIXELS).ceil() as usize + 1,
+            ..options.clone()
+        }
+    }
+}
+
+/// The sampled points of a function, split up wherever the function is undefined or jumps.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SampledCurve {
+    /// Continuous pieces of the curve, each ordered by x.
+    pub segments: Vec<Vec<[f64; 2]>>,
+    /// The x-positions of vertical asymptotes, like `x=0` for `1/x`.
+    ///
+    /// Only filled in if [`SampleOptions::detect_asymptotes`] is set.
+    pub asymptotes: Vec<f64>,
+}
+
+impl SampledCurve {
+    /// Splits samples ordered by x into continuous segments.
+    ///
+    /// Two neighbouring samples are considered a jump if they are far apart vertically even though
+    /// the sampler refined them down to the narrowest segment it allows,
+    /// and the gap between them is larger than the gaps next to it.
+    /// A steep but continuous curve, like `1/x` close to zero, has a growing gap towards the steepest part instead.
+    pub(crate) fn from_samples(samples: &[Sample], options: &SampleOptions) -> Self {
+        let [x_scale, y_scale] = options.scale.map(f64::abs);
+        // The vertical gap between every sample and the next one, if both are defined.
+        let gaps = samples
+            .windows(2)
+            .map(|pair| Some((pair[1].y? - pair[0].y?).abs()))
+            .collect::<Vec<_>>();
+        let is_jump = |i: usize| match gaps[i] {
+            Some(gap) => {
+                let is_largest = |neighbour: Option<&Option<f64>>| {
+                    neighbour
+                        .copied()
+                        .flatten()
+                        .is_none_or(|other| gap >= other)
+                };
+                (samples[i + 1].x - samples[i].x) * x_scale < MIN_SEGMENT_PIXELS
+                    && gap * y_scale > JUMP_PIXELS
+                    && is_largest(i.checked_sub(1).and_then(|before| gaps.get(before)))
+                    && is_largest(gaps.get(i + 1))
+            }
+            None => true,
+        };
+
+        let mut curve = Self::default();
+        let mut segment: Vec<[f64; 2]> = Vec::new();
+        for (i, sample) in samples.iter().enumerate() {
+            if i != 0 && is_jump(i - 1) {
+                curve.push_segment(std::mem::take(&mut segment), samples, i, options);
+            }
+            if let Some(y) = sample.y {
+                segment.push([sample.x, y]);
+            }
+        }
+        curve.push_segment(segment, samples, samples.len(), options);
+        curve
+    }
+
+    /// Adds a finished segment, and checks the break between it and the next one, starting at `next`,
+    /// for an asymptote.
+    fn push_segment(
+        &mut self,
+        segment: Vec<[f64; 2]>,
+        samples: &[Sample],
+        next: usize,
+        options: &SampleOptions,
+    ) {
+        let ended_curve = !segment.is_empty();
+        if segment.len() > 1 {
+            self.segments.push(segment);
+        }
+        if !options.detect_asymptotes || next == samples.len() {
+            return;
+        }
+        let left_end = samples[..next]
+            .iter()
+            .rposition(|sample| sample.y.is_some());
+        // Breaks inside a stretch where the function is undefined were already checked where it started,
+        // unless the function was undefined all the way from the start.
+        let starts_curve = left_end.is_none() && samples[next].y.is_some();
+        if !ended_curve && !starts_curve {
+            return;
+        }
+        let Some(right_start) = samples[next..]
+            .iter()
+            .position(|sample| sample.y.is_some())
+            .map(|start| next + start)
+        else {
+            return;
+        };
+
+        // Every side of the break that has a curve needs to shoot off towards infinity.
+        let y_scale = options.scale[1].abs();
+        let diverges = |near: usize, far: Option<usize>| {
+            let near = samples[near]
+                .y
+                .expect("Only called with defined samples")
+                .abs();
+            near * y_scale > JUMP_PIXELS
+                && far
+                    .and_then(|far| samples.get(far)?.y)
+                    .is_none_or(|far| near > far.abs())
+        };
+        let left_diverges = left_end.is_none_or(|end| diverges(end, end.checked_sub(1)));
+        if left_diverges && diverges(right_start, Some(right_start + 1)) {
+            let left_x = samples[left_end.unwrap_or(next - 1)].x;
+            self.asymptotes.push((left_x + samples[right_start].x) / 2.);
+        }
+    }
+}
+
+/// Evenly spaced points of a borrowed function over a finite range, see [`Function::samples`].
+#[derive(Debug, Clone)]
+pub struct SampleIter<'a> {
+    function: &'a Function,
+    binding: &'a Binding,
+    min_x: f64,
+    step: f64,
+    next: usize,
+    count: usize,
+}
+
+impl<'a> SampleIter<'a> {
+    pub(crate) fn new(
+        function: &'a Function,
+        min_x: f64,
+        max_x: f64,
+        count: usize,
+        binding: &'a Binding,
+    ) -> Self {
+        let step = if count > 1 {
+            (max_x - min_x) / (count - 1) as f64
+        } else {
+            0.
+        };
+        Self {
+            function,
+            binding,
+            min_x,
+            step,
+            next: 0,
+            count,
+        }
+    }
+}
+
+impl Iterator for SampleIter<'_> {
+    type Item = Result<(f64, f64), EvalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == self.count {
+            return None;
+        }
+        let x = self.min_x + self.step * self.next as f64;
+        self.next += 1;
+        Some(self.function.y_at(x, self.binding).map(|y| (x, y)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.count - self.next;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for SampleIter<'_> {}
+
+/// A calculated point, where `y` is `None` if the function is undefined at `x`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Sample {
+    pub x: f64,
+    pub y: Option<f64>,
+}
+
+/// The part of the curve between two samples, together with the sample in the middle of them.
+struct Segment {
+    left: Sample,
+    middle: Sample,
+    right: Sample,
+    /// How far, in pixels, the middle sample is from a straight line between the outer ones.
+    error: f64,
+}
+
+impl Segment {
+    fn new(left: Sample, middle: Sample, right: Sample, options: &SampleOptions) -> Self {
+        let [x_scale, y_scale] = options.scale.map(f64::abs);
+        let error = if (right.x - left.x) * x_scale < MIN_SEGMENT_PIXELS {
+            0.
+        } else {
+            match (left.y, middle.y, right.y) {
+                (Some(l), Some(m), Some(r)) => (m - (l + r) / 2.).abs() * y_scale,
+                (None, None, None) => 0.,
+                // The edge of where the function is defined is somewhere in the segment.
+                _ => f64::INFINITY,
+            }
+        };
+        Self {
+            left,
+            middle,
+            right,
+            error,
+        }
+    }
+}
+
+impl PartialEq for Segment {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Segment {}
+
+impl PartialOrd for Segment {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Segments are ordered by their error, so the worst one is refined first.
+impl Ord for Segment {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.error.total_cmp(&other.error)
+    }
+}
+
+/// Samples `function` between `min_x` and `max_x`, placing more points where the curve bends
+/// and fewer where it is straight, until the curve is within the tolerance or the point budget is spent.
+///
+/// `function` calculates many points at once, writing the y-value for every x-value to the second slice,
+/// or a non-finite value where the function is undefined.
+pub(crate) fn sample_adaptive(
+    mut function: impl FnMut(&[f64], &mut [f64]),
+    min_x: f64,
+    max_x: f64,
+    options: &SampleOptions,
+) -> Vec<Sample> {
+    let mut sampler = AdaptiveSampler::new(min_x, max_x, options.clone());
+    while !sampler.advance(&mut function, usize::MAX) {}
+    sampler.samples
+}
+
+/// Sampling like [`sample_adaptive`] does, but done a slice at a time,
+/// so it can be spread out when there is no time to do it all at once.
+pub(crate) struct AdaptiveSampler {
+    options: SampleOptions,
+    /// The evenly spaced x-values sampled first.
+    grid: Vec<f64>,
+    /// The samples calculated so far, starting with those of the grid in order.
+    samples: Vec<Sample>,
+    /// How many pairs of neighbouring grid samples get a segment before refining, as many as the budget allows.
+    initial_segments: usize,
+    /// The segments that can still be refined, worst first.
+    segments: BinaryHeap<Segment>,
+    /// The number of points calculated, or to be calculated for the segments there already are.
+    used: usize,
+    /// Reused for the y-values of every slice.
+    ys: Vec<f64>,
+}
+
+impl AdaptiveSampler {
+    pub(crate) fn new(min_x: f64, max_x: f64, options: SampleOptions) -> Self {
+        let max_points = options.max_points.max(2);
+        let initial_points = options.initial_points.clamp(2, max_points);
+        let step = (max_x - min_x) / (initial_points - 1) as f64;
+        let initial_segments = (initial_points - 1).min(max_points - initial_points);
+        Self {
+            options,
+            grid: (0..initial_points)
+                .map(|i| min_x + step * i as f64)
+                .collect(),
+            samples: Vec::new(),
+            initial_segments,
+            segments: BinaryHeap::new(),
+            used: initial_points + initial_segments,
+            ys: Vec::new(),
+        }
+    }
+
+    /// Calculates the next slice of at most `budget` points, and returns whether sampling is finished.
+    ///
+    /// First the grid is sampled, then the middle of every segment between its points,
+    /// and then every segment that is off by too much is split, worst first while the budget lasts.
+    /// Splitting a segment costs two new samples, one in the middle of each half.
+    pub(crate) fn advance(
+        &mut self,
+        mut function: impl FnMut(&[f64], &mut [f64]),
+        budget: usize,
+    ) -> bool {
+        let budget = budget.max(2);
+        let mut sample_all = |xs: &[f64]| {
+            self.ys.clear();
+            self.ys.resize(xs.len(), 0.);
+            function(xs, &mut self.ys);
+            xs.iter()
+                .zip(&self.ys)
+                .map(|(&x, &y)| Sample {
+                    x,
+                    y: Some(y).filter(|y| y.is_finite()),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let sampled = self.samples.len();
+        if sampled < self.grid.len() {
+            let end = self.grid.len().min(sampled.saturating_add(budget));
+            self.samples.extend(sample_all(&self.grid[sampled..end]));
+            return false;
+        }
+        let segmented = self.segments.len();
+        if segmented < self.initial_segments {
+            let end = self.initial_segments.min(segmented.saturating_add(budget));
+            let pairs = &self.samples[segmented..=end];
+            let middles = pairs
+                .windows(2)
+                .map(|pair| (pair[0].x + pair[1].x) / 2.)
+                .collect::<Vec<_>>();
+            for (pair, middle) in pairs.windows(2).zip(sample_all(&middles)) {
+                self.segments
+                    .push(Segment::new(pair[0], middle, pair[1], &self.options));
+            }
+            return false;
+        }
+
+        let max_points = self.options.max_points.max(2);
+        let mut halves = Vec::new();
+        while halves.len() + 2 <= budget
+            && self.used + 2 <= max_points
+            && self
+                .segments
+                .peek()
+                .is_some_and(|segment| segment.error > self.options.tolerance)
+        {
+            let segment = self.segments.pop().expect("Just peeked");
+            halves.push((segment.left, segment.middle));
+            halves.push((segment.middle, segment.right));
+            self.samples.push(segment.middle);
+            self.used += 2;
+        }
+        if halves.is_empty() {
+            self.samples
+                .extend(self.segments.drain().map(|segment| segment.middle));
+            self.samples.sort_by(|a, b| a.x.total_cmp(&b.x));
+            return true;
+        }
+        let middles = halves
+            .iter()
+            .map(|(left, right)| (left.x + right.x) / 2.)
+            .collect::<Vec<_>>();
+        for ((left, right), middle) in halves.into_iter().zip(sample_all(&middles)) {
+            self.segments
+                .push(Segment::new(left, middle, right, &self.options));
+        }
+        false
+    }
+
+    /// The sampled curve, once [`AdaptiveSampler::advance`] has finished.
+    pub(crate) fn curve(&self) -> SampledCurve {
+        SampledCurve::from_samples(&self.samples, &self.options)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::sampler::{
+        sample_adaptive, AdaptiveSampler, Sample, SampleOptions, SampledCurve, Viewport,
+    };
+    use crate::parse::{Binding, EvalError, Function};
+    use egui_plot::PlotBounds;
+
+    /// Samples a function calculating one point at a time, where errors make the point undefined.
+    fn sample(
+        function: impl Fn(f64) -> Result<f64, EvalError>,
+        min_x: f64,
+        max_x: f64,
+        options: &SampleOptions,
+    ) -> Vec<Sample> {
+        let batch = |xs: &[f64], ys: &mut [f64]| {
+            for (x, y) in xs.iter().zip(ys) {
+                *y = function(*x).unwrap_or(f64::NAN);
+            }
+        };
+        sample_adaptive(batch, min_x, max_x, options)
+    }
+
+    #[test]
+    fn straight_lines_stay_coarse() {
+        let options = SampleOptions::default();
+        let samples = sample(|x| Ok(2. * x + 1.), -10., 10., &options);
+        assert_eq!(samples.len(), options.initial_points * 2 - 1);
+        assert!(samples.windows(2).all(|pair| pair[0].x < pair[1].x));
+    }
+
+    #[test]
+    fn oscillation_gets_refined_within_budget() {
+        let options = SampleOptions {
+            scale: [1000., 100.],
+            max_points: 1000,
+            ..Default::default()
+        };
+        let samples = sample(|x| Ok((1. / x).sin()), 0.01, 1., &options);
+        assert!(samples.len() > 300);
+
+        // Most of the points should be where the curve oscillates quickly.
+        let dense = samples.iter().filter(|sample| sample.x < 0.2).count();
+        assert!(dense > samples.len() * 3 / 4);
+
+        let options = SampleOptions {
+            max_points: 200,
+            ..options
+        };
+        let samples = sample(|x| Ok((1. / x).sin()), 0.01, 1., &options);
+        assert!(samples.len() <= options.max_points);
+    }
+
+    #[test]
+    fn sampling_in_slices() {
+        let options = SampleOptions {
+            scale: [1000., 100.],
+            max_points: 1000,
+            ..Default::default()
+        };
+        let mut sampler = AdaptiveSampler::new(0.01, 1., options.clone());
+        let (mut calculated, mut slices) = (0, 0);
+        while !sampler.advance(
+            |xs, ys| {
+                assert!(xs.len() <= 50);
+                calculated += xs.len();
+                for (x, y) in xs.iter().zip(ys) {
+                    *y = (1. / x).sin();
+                }
+            },
+            50,
+        ) {
+            slices += 1;
+        }
+        assert!(slices > 10);
+        assert!(calculated <= options.max_points);
+        assert_eq!(sampler.samples.len(), calculated);
+        assert!(sampler.samples.windows(2).all(|pair| pair[0].x < pair[1].x));
+    }
+
+    #[test]
+    fn undefined_points_are_kept() {
+        let samples = sample(
+            |x| {
+                if x < 0. {
+                    Err(EvalError::DomainError("sqrt".to_string(), x))
+                } else {
+                    Ok(x.sqrt())
+                }
+            },
+            -1.,
+            1.,
+            &SampleOptions::default(),
+        );
+        assert!(samples.iter().any(|sample| sample.y.is_none()));
+        assert!(samples.iter().any(|sample| sample.y.is_some()));
+    }
+
+    fn curve(
+        function: impl Fn(f64) -> Result<f64, EvalError>,
+        min_x: f64,
+        max_x: f64,
+    ) -> SampledCurve {
+        let options = SampleOptions {
+            scale: [100., 100.],
+            detect_asymptotes: true,
+            ..Default::default()
+        };
+        let samples = sample(function, min_x, max_x, &options);
+        SampledCurve::from_samples(&samples, &options)
+    }
+
+    #[test]
+    fn asymptotes_split_the_curve() {
+        let reciprocal = curve(
+            |x| {
+                if x == 0. {
+                    Err(EvalError::DivisionByZero)
+                } else {
+                    Ok(1. / x)
+                }
+            },
+            -1.,
+            1.3,
+        );
+        assert_eq!(reciprocal.segments.len(), 2);
+        assert!(reciprocal.segments[0].iter().all(|[x, _]| *x < 0.));
+        assert_eq!(
+            reciprocal.asymptotes.len(),
+            1,
+            "{:?}",
+            reciprocal.asymptotes
+        );
+        assert!(reciprocal.asymptotes[0].abs() < 0.01);
+
+        let tan = curve(|x| Ok(x.tan()), -5., 5.);
+        assert_eq!(tan.segments.len(), 5);
+        assert_eq!(tan.asymptotes.len(), 4);
+        for pole in [-3., -1., 1., 3.].map(|n| n * std::f64::consts::FRAC_PI_2) {
+            assert!(tan.asymptotes.iter().any(|x| (x - pole).abs() < 0.01));
+        }
+
+        let domain = |function: fn(f64) -> f64| {
+            move |x: f64| {
+                if x <= 0. {
+                    Err(EvalError::DomainError("test".to_string(), x))
+                } else {
+                    Ok(function(x))
+                }
+            }
+        };
+        let ln = curve(domain(f64::ln), -1., 1.);
+        assert_eq!(ln.segments.len(), 1);
+        assert_eq!(ln.asymptotes.len(), 1);
+        assert!(ln.asymptotes[0].abs() < 0.01);
+        assert!(curve(domain(f64::sqrt), -1., 1.).asymptotes.is_empty());
+    }
+
+    #[test]
+    fn jumps_split_the_curve() {
+        let floor = curve(|x| Ok((x * 2.).floor() * 2.), -1.1, 1.1);
+        assert_eq!(floor.segments.len(), 6);
+        assert!(floor.asymptotes.is_empty());
+
+        let smooth = curve(|x| Ok(x.powi(3)), -3., 3.);
+        assert_eq!(smooth.segments.len(), 1);
+        assert!(smooth.asymptotes.is_empty());
+    }
+
+    #[test]
+    fn viewport_range_and_resolution() {
+        let viewport = Viewport::new(
+            PlotBounds::from_min_max([-2., -50.], [6., 50.]),
+            [800., 400.],
+        );
+        assert_eq!(viewport.visible_x(), (-2., 6.));
+        let (min_x, max_x) = viewport.sample_x();
+        assert!(min_x < -2. && max_x > 6.);
+        assert!(max_x - min_x < 8. * 2.);
+
+        let options = viewport.sample_options(&SampleOptions::default());
+        assert_eq!(options.scale, [100., 4.]);
+
+        // Twice the pixels means twice the points to start with.
+        let wide = Viewport::new(viewport.bounds, [1600., 400.]);
+        let wide_options = wide.sample_options(&SampleOptions::default());
+        assert_eq!(
+            wide_options.initial_points - 1,
+            (options.initial_points - 1) * 2
+        );
+    }
+
+    #[test]
+    fn sample_iter_is_finite_and_shared() {
+        let function = Function::try_from("f(x)=x^2").unwrap();
+        let binding = Binding::default();
+        let points = function
+            .samples(-1., 1., 5, &binding)
+            .collect::<Result<Vec<_>, _>>();
+        assert_eq!(
+            points,
+            Ok(vec![
+                (-1., 1.),
+                (-0.5, 0.25),
+                (0., 0.),
+                (0.5, 0.25),
+                (1., 1.)
+            ])
+        );
+        assert_eq!(function.samples(0., 1., 0, &binding).count(), 0);
+        assert_eq!(
+            function.samples(3., 5., 1, &binding).next(),
+            Some(Ok((3., 9.)))
+        );
+
+        // Several views can sample the same function at once.
+        let sums = std::thread::scope(|scope| {
+            [(-2., 0.), (0., 2.)]
+                .map(|(min_x, max_x)| {
+                    let (function, binding) = (&function, &binding);
+                    scope.spawn(move || {
+                        function
+                            .samples(min_x, max_x, 101, binding)
+                            .map(|point| point.unwrap().1)
+                            .sum::<f64>()
+                    })
+                })
+                .map(|view| view.join().unwrap())
+        });
+        assert!((sums[0] - sums[1]).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::parse::Function;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Named functions that expressions can call, shared between definitions.
+///
+/// Calls are inlined when a function is parsed, so a function calling another one
+/// has to be parsed again whenever the other one changes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scope {
+    functions: HashMap<String, Arc<Function>>,
+    /// Functions that can be called without being defined, with the number of arguments they take.
+    declared: HashMap<String, usize>,
+    /// Named numbers every expression can use like a variable, see [`Parameter`](crate::parse::Parameter).
+    parameters: HashMap<String, f64>,
+}
+
+impl Scope {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `function` under its name, unless a function with that name is already defined.
+    ///
+    /// Returns whether it was added.
+    pub fn define(&mut self, function: Arc<Function>) -> bool {
+        if self.functions.contains_key(&function.name) {
+            return false;
+        }
+        self.declared.remove(&function.name);
+        self.functions.insert(function.name.clone(), function);
+        true
+    }
+
+    /// Lets expressions call `name` with `arity` arguments before it is defined,
+    /// so they can be parsed, but calculating them fails with [`EvalError::DependencyError`](crate::parse::EvalError::DependencyError).
+    ///
+    /// Does nothing if a function called `name` is already defined or declared.
+    pub fn declare(&mut self, name: &str, arity: usize) {
+        if !self.functions.contains_key(name) {
+            self.declared.entry(name.to_string()).or_insert(arity);
+        }
+    }
+
+    /// The function called `name`, if any.
+    pub fn get(&self, name: &str) -> Option<&Function> {
+        self.functions.get(name).map(Arc::as_ref)
+    }
+
+    /// Sets the parameter `name` to `value`, adding it if there is none with that name.
+    pub fn set_parameter(&mut self, name: &str, value: f64) {
+        self.parameters.insert(name.to_string(), value);
+    }
+
+    /// The value of the parameter `name`, if any.
+    pub fn parameter(&self, name: &str) -> Option<f64> {
+        self.parameters.get(name).copied()
+    }
+
+    /// The names of every parameter, in no particular order.
+    pub fn parameters(&self) -> impl Iterator<Item = &str> {
+        self.parameters.keys().map(String::as_str)
+    }
+
+    /// Whether `name` is declared, but not defined.
+    pub fn is_undefined(&self, name: &str) -> bool {
+        self.declared.contains_key(name)
+    }
+
+    /// Every function in the scope, in no particular order.
+    pub fn functions(&self) -> impl Iterator<Item = &Function> {
+        self.functions.values().map(Arc::as_ref)
+    }
+
+    /// The name and number of arguments of every function that can be called, defined or not.
+    pub(crate) fn signatures(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.functions()
+            .map(|function| (function.name.as_str(), function.variables().len()))
+            .chain(
+                self.declared
+                    .iter()
+                    .map(|(name, arity)| (name.as_str(), *arity)),
+            )
+    }
+}
+
+/// Collects functions into a scope, keeping the first one of every name.
+impl FromIterator<Arc<Function>> for Scope {
+    fn from_iter<I: IntoIterator<Item = Arc<Function>>>(functions: I) -> Self {
+        let mut scope = Self::new();
+        for function in functions {
+            scope.define(function);
+        }
+        scope
+    }
+}
@@ -0,0 +1,575 @@
+use crate::parse::builtins::Builtin;
+use crate::parse::expr::{BinaryOp, Expr, UnaryOp};
+use crate::parse::limits::Limits;
+use std::fmt;
+
+impl Expr {
+    /// An equivalent expression that is easier to read and quicker to calculate.
+    ///
+    /// Numbers are folded together, identities like `x*1`, `x+0` and `x^1` are removed,
+    /// like terms are collected, so `2x+3x` becomes `5x`, and powers of the same base are merged,
+    /// so `x*x^2` becomes `x^3`.
+    /// Divisions, powers and calls with only numbers are only folded if the result is a whole number,
+    /// so that `1/3` and `ln(2)` stay exact.
+    ///
+    /// Like in algebra by hand, every part is assumed to be defined, so `x/x` becomes `1` even though it is undefined at 0.
+    pub fn simplified(&self) -> Expr {
+        self.simplified_with_limits(&Limits::default())
+    }
+
+    /// [`Expr::simplified`], which leaves parts nested deeper than `limits` allow as they are.
+    pub fn simplified_with_limits(&self, limits: &Limits) -> Expr {
+        simplify(self, limits.max_evaluation_depth)
+    }
+
+    /// Writes the expression with only the parentheses it needs, like `2x^2+1` instead of `((2*(x^2))+1)`.
+    pub fn canonical(&self) -> Canonical<'_> {
+        Canonical(self)
+    }
+}
+
+/// Simplifies `expr`, with `depth_left` levels of nesting left before it is too deep to simplify.
+fn simplify(expr: &Expr, depth_left: usize) -> Expr {
+    // Too deep to simplify without risking a stack overflow, but it's still correct as it is.
+    if depth_left == 0 {
+        return expr.clone();
+    }
+    let depth_left = depth_left - 1;
+    match expr {
+        Expr::Number(_) | Expr::Constant(_) | Expr::Variable(_) => expr.clone(),
+        Expr::Unary(..) | Expr::Binary(BinaryOp::Add | BinaryOp::Subtract, ..) => {
+            let mut sum = Sum::default();
+            sum.add(expr, 1., depth_left);
+            sum.build()
+        }
+        Expr::Binary(BinaryOp::Multiply | BinaryOp::Divide, ..) => {
+            let mut product = Product::default();
+            product.multiply(expr, false, depth_left);
+            product.build(depth_left)
+        }
+        Expr::Binary(BinaryOp::Pow, base, exponent) => power(
+            simplify(base, depth_left),
+            simplify(exponent, depth_left),
+            depth_left,
+        ),
+        Expr::Call(name, args) => {
+            let args = args
+                .iter()
+                .map(|arg| simplify(arg, depth_left))
+                .collect::<Vec<_>>();
+            let numbers = args
+                .iter()
+                .map(|arg| match arg {
+                    Expr::Number(num) => Some(*num),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>();
+            numbers
+                .zip(Builtin::from_name(name))
+                .filter(|(numbers, builtin)| builtin.arity().contains(&numbers.len()))
+                .and_then(|(numbers, builtin)| builtin.checked_apply(&numbers).ok())
+                .filter(|result| is_whole(*result))
+                .map_or_else(|| Expr::Call(name.clone(), args), Expr::Number)
+        }
+    }
+}
+
+fn is_whole(num: f64) -> bool {
+    num.fract() == 0.
+}
+
+fn is_number(expr: &Expr, number: f64) -> bool {
+    matches!(expr, Expr::Number(num) if *num == number)
+}
+
+/// `base^exponent` for simplified operands, with the power folded or removed where possible.
+fn power(base: Expr, exponent: Expr, depth_left: usize) -> Expr {
+    match (base, exponent) {
+        (Expr::Number(base), Expr::Number(exponent))
+            if BinaryOp::Pow
+                .checked_apply(base, exponent)
+                .is_ok_and(is_whole) =>
+        {
+            Expr::Number(BinaryOp::Pow.apply(base, exponent))
+        }
+        (_, exponent) if is_number(&exponent, 0.) => Expr::Number(1.),
+        (base, exponent) if is_number(&exponent, 1.) => base,
+        (base, _) if is_number(&base, 1.) => Expr::Number(1.),
+        // Only whole outer exponents, since `(x^2)^0.5` is `abs(x)` rather than `x`.
+        (Expr::Binary(BinaryOp::Pow, base, inner), Expr::Number(exponent))
+            if is_whole(exponent) =>
+        {
+            let exponent = simplify(
+                &Expr::binary(BinaryOp::Multiply, *inner, Expr::Number(exponent)),
+                depth_left,
+            );
+            power(*base, exponent, depth_left)
+        }
+        (base, exponent) => Expr::binary(BinaryOp::Pow, base, exponent),
+    }
+}
+
+/// A sum being collected, with the coefficient of every term as a fraction in the order they first appear,
+/// and the sum of the numbers in it.
+struct Sum {
+    terms: Vec<(Fraction, Expr)>,
+    constant: Fraction,
+}
+
+impl Default for Sum {
+    fn default() -> Self {
+        Self {
+            terms: Vec::new(),
+            constant: (0., 1.),
+        }
+    }
+}
+
+impl Sum {
+    /// Adds `expr` times `sign`, which is `1` or `-1`.
+    fn add(&mut self, expr: &Expr, sign: f64, depth_left: usize) {
+        match expr {
+            _ if depth_left == 0 => self.terms.push(((sign, 1.), expr.clone())),
+            Expr::Binary(BinaryOp::Add, lhs, rhs) => {
+                self.add(lhs, sign, depth_left - 1);
+                self.add(rhs, sign, depth_left - 1);
+            }
+            Expr::Binary(BinaryOp::Subtract, lhs, rhs) => {
+                self.add(lhs, sign, depth_left - 1);
+                self.add(rhs, -sign, depth_left - 1);
+            }
+            Expr::Unary(UnaryOp::Negate, operand) => self.add(operand, -sign, depth_left - 1),
+            _ => match simplify(expr, depth_left) {
+                // A term can simplify to a sum, like `1*(x+1)`, whose terms are then collected as well.
+                term @ (Expr::Unary(..) | Expr::Binary(BinaryOp::Add | BinaryOp::Subtract, ..)) => {
+                    self.add(&term, sign, depth_left - 1)
+                }
+                term => {
+                    let ((numerator, denominator), term) = split_coefficient(term);
+                    let coefficient = (sign * numerator, denominator);
+                    let Some(term) = term else {
+                        self.constant = add_fractions(self.constant, coefficient);
+                        return;
+                    };
+                    match self
+                        .terms
+                        .iter_mut()
+                        .find(|(_, existing)| *existing == term)
+                    {
+                        Some((existing, _)) => *existing = add_fractions(*existing, coefficient),
+                        None => self.terms.push((coefficient, term)),
+                    }
+                }
+            },
+        }
+    }
+
+    /// The collected sum, with terms subtracted rather than added with a negative coefficient,
+    /// and the numbers last.
+    fn build(self) -> Expr {
+        let terms = self
+            .terms
+            .into_iter()
+            .chain([(self.constant, Expr::Number(1.))])
+            .filter(|((numerator, _), _)| *numerator != 0.);
+        let mut sum: Option<Expr> = None;
+        for ((numerator, denominator), term) in terms {
+            let magnitude = |numerator: f64| with_fraction((numerator, denominator), term.clone());
+            sum = Some(match sum {
+                None => magnitude(numerator),
+                Some(sum) if numerator < 0. => {
+                    Expr::binary(BinaryOp::Subtract, sum, magnitude(-numerator))
+                }
+                Some(sum) => Expr::binary(BinaryOp::Add, sum, magnitude(numerator)),
+            });
+        }
+        sum.unwrap_or(Expr::Number(0.))
+    }
+}
+
+/// A product being collected, with the numbers in it as a fraction, and the exponent of every base
+/// in the order they first appear.
+struct Product {
+    numerator: f64,
+    denominator: f64,
+    powers: Vec<(Expr, Expr)>,
+}
+
+impl Default for Product {
+    fn default() -> Self {
+        Self {
+            numerator: 1.,
+            denominator: 1.,
+            powers: Vec::new(),
+        }
+    }
+}
+
+impl Product {
+    /// Multiplies by `expr`, or divides by it if `divide` is set.
+    fn multiply(&mut self, expr: &Expr, divide: bool, depth_left: usize) {
+        match expr {
+            _ if depth_left == 0 => self.multiply_power(expr.clone(), divide, depth_left),
+            Expr::Binary(BinaryOp::Multiply, lhs, rhs) => {
+                self.multiply(lhs, divide, depth_left - 1);
+                self.multiply(rhs, divide, depth_left - 1);
+            }
+            Expr::Binary(BinaryOp::Divide, lhs, rhs) => {
+                self.multiply(lhs, divide, depth_left - 1);
+                self.multiply(rhs, !divide, depth_left - 1);
+            }
+            _ => self.multiply_simplified(simplify(expr, depth_left), divide, depth_left - 1),
+        }
+    }
+
+    fn multiply_simplified(&mut self, factor: Expr, divide: bool, depth_left: usize) {
+        match factor {
+            // Dividing by zero is kept as it is, so it still fails when calculated.
+            Expr::Number(num) if divide && num != 0. => self.denominator *= num,
+            Expr::Number(num) if !divide => self.numerator *= num,
+            // A factor can simplify to a product, like `(x*y)^1`, whose factors are then collected as well.
+            Expr::Binary(BinaryOp::Multiply | BinaryOp::Divide, ..) => {
+                self.multiply(&factor, divide, depth_left)
+            }
+            Expr::Unary(UnaryOp::Negate, operand) => {
+                self.numerator = -self.numerator;
+                self.multiply_simplified(*operand, divide, depth_left);
+            }
+            factor => self.multiply_power(factor, divide, depth_left),
+        }
+    }
+
+    /// Multiplies by `factor`, or divides by it, by adding to the exponent of its base.
+    fn multiply_power(&mut self, factor: Expr, divide: bool, depth_left: usize) {
+        let (base, exponent) = match factor {
+            Expr::Binary(BinaryOp::Pow, base, exponent) => (*base, *exponent),
+            factor => (factor, Expr::Number(1.)),
+        };
+        let op = if divide {
+            BinaryOp::Subtract
+        } else {
+            BinaryOp::Add
+        };
+        match self
+            .powers
+            .iter_mut()
+            .find(|(existing, _)| *existing == base)
+        {
+            Some((_, existing)) => {
+                *existing = simplify(&Expr::binary(op, existing.clone(), exponent), depth_left);
+            }
+            None => {
+                let exponent = simplify(&Expr::binary(op, Expr::Number(0.), exponent), depth_left);
+                self.powers.push((base, exponent));
+            }
+        }
+    }
+
+    /// The collected product, with the coefficient first and a single division by everything with a negative exponent.
+    fn build(self, depth_left: usize) -> Expr {
+        let (mut numerator, mut denominator) = (self.numerator, self.denominator);
+        let divides_by_zero = self
+            .powers
+            .iter()
+            .any(|(base, exponent)| is_number(base, 0.) && !is_number(exponent, 0.));
+        if numerator == 0. && !divides_by_zero {
+            return Expr::Number(0.);
+        }
+        // Reduced, so that equal coefficients look the same.
+        (numerator, denominator) = reduce((numerator, denominator));
+
+        let mut above = Vec::new();
+        let mut below = Vec::new();
+        for (base, exponent) in self.powers {
+            match exponent {
+                Expr::Number(0.) => (),
+                Expr::Number(exponent) if exponent < 0. => {
+                    below.push(power(base, Expr::Number(-exponent), depth_left))
+                }
+                exponent => above.push(power(base, exponent, depth_left)),
+            }
+        }
+        let with_factors = |coefficient: f64, factors: Vec<Expr>| {
+            factors
+                .into_iter()
+                .reduce(|product, factor| Expr::binary(BinaryOp::Multiply, product, factor))
+                .map_or(Expr::Number(coefficient), |product| {
+                    with_coefficient(coefficient, product)
+                })
+        };
+        let top = with_factors(numerator, above);
+        if below.is_empty() && denominator == 1. {
+            top
+        } else {
+            Expr::binary(BinaryOp::Divide, top, with_factors(denominator, below))
+        }
+    }
+}
+
+/// A number as a numerator and a denominator, so that coefficients like `1/3` stay exact.
+type Fraction = (f64, f64);
+
+/// The fraction in lowest terms with a positive denominator,
+/// or as a single number if it isn't made of whole numbers.
+fn reduce((numerator, denominator): Fraction) -> Fraction {
+    if is_whole(numerator) && is_whole(denominator) && denominator != 0. {
+        let divisor = gcd(numerator.abs(), denominator.abs()).copysign(denominator);
+        (numerator / divisor, denominator / divisor)
+    } else {
+        (numerator / denominator, 1.)
+    }
+}
+
+fn add_fractions(
+    (lhs_numerator, lhs_denominator): Fraction,
+    (rhs_numerator, rhs_denominator): Fraction,
+) -> Fraction {
+    reduce((
+        lhs_numerator * rhs_denominator + rhs_numerator * lhs_denominator,
+        lhs_denominator * rhs_denominator,
+    ))
+}
+
+/// The greatest common divisor of two whole numbers.
+fn gcd(mut a: f64, mut b: f64) -> f64 {
+    while b != 0. {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// `coefficient` times the simplified `term`, with the coefficient placed first, like in `2x*y`.
+fn with_coefficient(coefficient: f64, term: Expr) -> Expr {
+    match term {
+        _ if coefficient == 1. => term,
+        Expr::Number(num) => Expr::Number(coefficient * num),
+        Expr::Binary(op @ (BinaryOp::Multiply | BinaryOp::Divide), lhs, rhs) => {
+            Expr::binary(op, with_coefficient(coefficient, *lhs), *rhs)
+        }
+        term if coefficient == -1. => Expr::unary(UnaryOp::Negate, term),
+        term => Expr::binary(BinaryOp::Multiply, Expr::Number(coefficient), term),
+    }
+}
+
+/// The fraction `coefficient` times the simplified `term`, written like [`Product`] would, as in `3x/2`.
+fn with_fraction((numerator, denominator): Fraction, term: Expr) -> Expr {
+    let (above, below) = match term {
+        Expr::Binary(BinaryOp::Divide, above, below) => (*above, Some(*below)),
+        term => (term, None),
+    };
+    let above = with_coefficient(numerator, above);
+    match below {
+        None if denominator == 1. => above,
+        None => Expr::binary(BinaryOp::Divide, above, Expr::Number(denominator)),
+        Some(below) => Expr::binary(
+            BinaryOp::Divide,
+            above,
+            with_coefficient(denominator, below),
+        ),
+    }
+}
+
+/// Splits a simplified term into its coefficient and the rest, if there is anything else.
+///
+/// The reverse of [`with_fraction`].
+fn split_coefficient(term: Expr) -> (Fraction, Option<Expr>) {
+    match term {
+        Expr::Number(num) => ((num, 1.), None),
+        Expr::Unary(UnaryOp::Negate, operand) => {
+            let ((numerator, denominator), rest) = split_coefficient(*operand);
+            ((-numerator, denominator), rest)
+        }
+        Expr::Binary(BinaryOp::Multiply, lhs, rhs) => match split_coefficient(*lhs) {
+            (coefficient, Some(lhs)) => (
+                coefficient,
+                Some(Expr::binary(BinaryOp::Multiply, lhs, *rhs)),
+            ),
+            (coefficient, None) => (coefficient, Some(*rhs)),
+        },
+        Expr::Binary(BinaryOp::Divide, lhs, rhs) => {
+            let ((numerator, denominator), above) = split_coefficient(*lhs);
+            let ((below_numerator, below_denominator), below) = split_coefficient(*rhs);
+            // Dividing by zero is kept as it is, so it still fails when calculated.
+            if below_numerator == 0. {
+                let above = above.unwrap_or(Expr::Number(1.));
+                let below = with_fraction(
+                    (below_numerator, below_denominator),
+                    below.unwrap_or(Expr::Number(1.)),
+                );
+                return (
+                    (numerator, denominator),
+                    Some(Expr::binary(BinaryOp::Divide, above, below)),
+                );
+            }
+            let coefficient =
+                reduce((numerator * below_denominator, denominator * below_numerator));
+            let rest = match (above, below) {
+                (above, None) => above,
+                (above, Some(below)) => Some(Expr::binary(
+                    BinaryOp::Divide,
+                    above.unwrap_or(Expr::Number(1.)),
+                    below,
+                )),
+            };
+            (coefficient, rest)
+        }
+        term => ((1., 1.), Some(term)),
+    }
+}
+
+/// An expression written with only the parentheses it needs, see [`Expr::canonical`].
+#[derive(Debug, Clone, Copy)]
+pub struct Canonical<'a>(&'a Expr);
+
+/// How tightly the expression holds together when written out,
+/// where an operand needs parentheses if it holds together less tightly than its operator.
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::Binary(BinaryOp::Add | BinaryOp::Subtract, ..) => 1,
+        Expr::Binary(BinaryOp::Multiply | BinaryOp::Divide, ..) => 2,
+        Expr::Unary(UnaryOp::Negate, _) => 3,
+        Expr::Number(num) if num.is_sign_negative() => 3,
+        Expr::Binary(BinaryOp::Pow, ..) => 4,
+        Expr::Number(_) | Expr::Constant(_) | Expr::Variable(_) | Expr::Call(..) => 5,
+    }
+}
+
+impl fmt::Display for Canonical<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operand = |expr: &Expr, parenthesize: bool| {
+            if parenthesize {
+                format!("({})", expr.canonical())
+            } else {
+                expr.canonical().to_string()
+            }
+        };
+        match self.0 {
+            Expr::Unary(op, inner) => write!(f, "{op}{}", operand(inner, precedence(inner) < 4)),
+            Expr::Binary(op, lhs, rhs) => {
+                let own = precedence(self.0);
+                let (lhs_parenthesized, rhs_parenthesized) = match op {
+                    // Powers group from the right, so `x^y^z` is `x^(y^z)`.
+                    BinaryOp::Pow => (precedence(lhs) <= own, precedence(rhs) < own),
+                    // The other operators group from the left, and a sign right after one reads badly.
+                    _ => (
+                        precedence(lhs) < own,
+                        precedence(rhs) <= own || precedence(rhs) == 3,
+                    ),
+                };
+                let (lhs_text, rhs_text) = (
+                    operand(lhs, lhs_parenthesized),
+                    operand(rhs, rhs_parenthesized),
+                );
+                // Numbers are multiplied by juxtaposition where it parses the same, like `2x` or `3(x+1)`.
+                let is_implicit = *op == BinaryOp::Multiply
+                    && matches!(lhs.as_ref(), Expr::Number(_))
+                    && rhs_text.starts_with(|c: char| c.is_alphabetic() || c == '(');
+                if is_implicit {
+                    write!(f, "{lhs_text}{rhs_text}")
+                } else {
+                    write!(f, "{lhs_text}{op}{rhs_text}")
+                }
+            }
+            Expr::Call(name, args) => {
+                write!(f, "{name}(")?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", arg.canonical())?;
+                }
+                write!(f, ")")
+            }
+            atom => write!(f, "{atom}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::TokenQueue;
+    use std::collections::HashMap;
+
+    fn parse(input: &str) -> TokenQueue {
+        TokenQueue::new(input, &["x".to_string(), "y".to_string()]).unwrap()
+    }
+
+    fn simplified(input: &str) -> String {
+        parse(input).expr().simplified().canonical().to_string()
+    }
+
+    #[test]
+    fn simplification() {
+        let cases = [
+            ("x*1+0", "x"),
+            ("2+3*4", "14"),
+            ("2x+3x", "5x"),
+            ("x+y-x+2y", "3y"),
+            ("x-x", "0"),
+            ("x*x^2", "x^3"),
+            ("x^2/x^5", "1/x^3"),
+            ("x/x", "1"),
+            ("(x^2)^3", "x^6"),
+            ("2^x*2^x", "2^(2x)"),
+            ("-(-x)", "x"),
+            ("-x*y", "-x*y"),
+            ("0-2x", "-2x"),
+            ("1/3", "1/3"),
+            ("6x/4", "3x/2"),
+            ("sqrt(16)+ln(2)", "ln(2)+4"),
+            ("x/0", "x/0"),
+            ("3(x+1)-(x+1)2", "x+1"),
+            ("1/2+1/2", "1"),
+            ("x/2+x/2", "x"),
+            ("x^2/2+x^2/2", "x^2"),
+            ("x/2+x/3", "5x/6"),
+            ("x^(2/3)*x^(1/3)", "x"),
+            ("1/2-y/4+1/4", "-y/4+3/4"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(simplified(input), expected, "simplifying {input}");
+        }
+
+        // Simplifying never changes the value where the original is defined.
+        let values = HashMap::from([("x".to_string(), 1.7), ("y".to_string(), -0.3)]);
+        for input in [
+            "2x^2*3x/(6x)-x^2",
+            "(x+y)^2/(x+y)+sin(x)*2sin(x)",
+            "x^y*x^(2y)/x",
+            "4/6*x-(-y)^3+e^x*e",
+        ] {
+            let expr = parse(input).expr().clone();
+            let original = expr.evaluate(&values).unwrap();
+            let simplified = expr.simplified().evaluate(&values).unwrap();
+            assert!(
+                (original - simplified).abs() < 1e-12,
+                "{input} is {original}, but {} is {simplified}",
+                expr.simplified().canonical()
+            );
+        }
+    }
+
+    #[test]
+    fn canonical_printing() {
+        for (input, expected) in [
+            ("x-(y-x)", "x-(y-x)"),
+            ("(x-y)-x", "x-y-x"),
+            ("x/(y*x)", "x/(y*x)"),
+            ("2^(3^x)", "2^3^x"),
+            ("(2^3)^x", "(2^3)^x"),
+            ("-x^2", "-x^2"),
+            ("(-x)^2", "(-x)^2"),
+            ("x^(-y)", "x^(-y)"),
+            ("x*(-y)", "x*(-y)"),
+            ("2*(x+1)", "2(x+1)"),
+            ("2*sin(x)*3", "2sin(x)*3"),
+            ("2*3", "2*3"),
+            ("max(x,y+1)", "max(x,y+1)"),
+        ] {
+            let expr = parse(input).expr().clone();
+            let printed = expr.canonical().to_string();
+            assert_eq!(printed, expected, "printing {input}");
+            assert_eq!(parse(&printed).expr(), &expr, "parsing {printed}");
+        }
+    }
+}
@@ -1,87 +1,16 @@
-use crate::parse::math_functions::ParseError;
+use crate::parse::builtins::{Builtin, Constant};
+use crate::parse::derivative::DIFF;
+use crate::parse::expr::{BinaryOp, Expr, UnaryOp};
+use crate::parse::limits::Limits;
+use crate::parse::math_functions::{EvalError, ParseError, Span};
+use crate::parse::scope::Scope;
 use prse::{try_parse, Parse};
-use regex::Regex;
 use std::collections::HashMap;
+use std::fmt;
 use std::iter::Peekable;
-use std::str::Chars;
-
-pub(crate) trait Operation {
-    fn do_operation(&self) -> f64;
-    fn required_args(&self) -> usize {
-        2
-    }
-
-    fn operation_type(&self) -> Token;
-}
-
-struct Add(f64, f64);
-
-impl Operation for Add {
-    fn do_operation(&self) -> f64 {
-        self.0 + self.1
-    }
-    fn operation_type(&self) -> Token {
-        Token::Add
-    }
-}
-struct Subtract(f64, f64);
-impl Operation for Subtract {
-    fn do_operation(&self) -> f64 {
-        self.0 - self.1
-    }
-
-    fn operation_type(&self) -> Token {
-        Token::Subtract
-    }
-}
-
-struct Multiply(f64, f64);
-impl Operation for Multiply {
-    fn do_operation(&self) -> f64 {
-        self.0 * self.1
-    }
-
-    fn operation_type(&self) -> Token {
-        Token::Multiply
-    }
-}
-
-struct Divide(f64, f64);
-
-impl Operation for Divide {
-    fn do_operation(&self) -> f64 {
-        self.0 / self.1
-    }
-
-    fn operation_type(&self) -> Token {
-        Token::Divide
-    }
-}
-
-struct Pow(f64, f64);
-
-impl Operation for Pow {
-    fn do_operation(&self) -> f64 {
-        self.0.powf(self.1)
-    }
-    fn operation_type(&self) -> Token {
-        Token::Pow
-    }
-}
-
-struct TestFunction(f64, f64);
-impl Operation for TestFunction {
-    fn do_operation(&self) -> f64 {
-        self.0 * self.1 / 2.
-    }
-
-    fn operation_type(&self) -> Token {
-        Token::TestFunction {
-            a: self.0,
-            b: self.1,
-        }
-    }
-}
+use std::ops::RangeInclusive;
+use std::str::CharIndices;
+use std::vec::IntoIter;
 
 #[derive(Parse, Debug, PartialEq, Clone)]
 pub(crate) enum Token {
@@ -95,17 +24,6 @@ pub(crate) enum Token {
     Divide,
     #[prse = "^"]
     Pow,
-    #[prse = "test({a},{b})"]
-    TestFunction { a: f64, b: f64 },
-}
-struct Function(FunctionType);
-impl Function {
-    fn test(&self) {
-        println!("Test")
-    }
-}
-enum FunctionType {
-    Test,
 }
 
 impl Token {
@@ -114,197 +32,535 @@ impl Token {
         try_parse!(&input, "{}").ok()
     }
 
-    fn to_operation(&self, num1: f64, num2: f64) -> Box<dyn Operation> {
-        let (n1, n2) = (num1, num2);
+    /// The operator the token stands for when used between two operands, if any.
+    fn binary_op(&self) -> Option<BinaryOp> {
         match self {
-            Self::Add => Box::new(Add(n1, n2)),
-            Self::Subtract => Box::new(Subtract(n1, n2)),
-            Self::Multiply => Box::new(Multiply(n1, n2)),
-            Self::Divide => Box::new(Divide(n1, n2)),
-            Self::Pow => Box::new(Pow(n1, n2)),
-            Self::TestFunction { a, b } => Box::new(TestFunction(*a, *b)),
+            Self::Add => Some(BinaryOp::Add),
+            Self::Subtract => Some(BinaryOp::Subtract),
+            Self::Multiply => Some(BinaryOp::Multiply),
+            Self::Divide => Some(BinaryOp::Divide),
+            Self::Pow => Some(BinaryOp::Pow),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct TokenQueue {
-    queue_items: Vec<QueueItem>,
-    pub input_representation: String,
+impl BinaryOp {
+    /// The left and right binding power of the operator.
+    ///
+    /// A right binding power higher than the left one makes the operator left-associative,
+    /// a lower one makes it right-associative.
+    fn binding_power(self) -> (u8, u8) {
+        match self {
+            Self::Add | Self::Subtract => (1, 2),
+            Self::Multiply | Self::Divide => (3, 4),
+            Self::Pow => (6, 5),
+        }
+    }
+}
+
+impl From<BinaryOp> for Token {
+    fn from(op: BinaryOp) -> Self {
+        match op {
+            BinaryOp::Add => Self::Add,
+            BinaryOp::Subtract => Self::Subtract,
+            BinaryOp::Multiply => Self::Multiply,
+            BinaryOp::Divide => Self::Divide,
+            BinaryOp::Pow => Self::Pow,
+        }
+    }
 }
 
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Add => write!(f, "+"),
+            Self::Subtract => write!(f, "-"),
+            Self::Multiply => write!(f, "*"),
+            Self::Divide => write!(f, "/"),
+            Self::Pow => write!(f, "^"),
+        }
+    }
+}
+
+/// A single lexical element of an expression, produced before the expression is parsed into a tree.
 #[derive(Debug, Clone, PartialEq)]
-enum QueueItem {
-    Variable(String),
+enum Lexeme {
     Number(f64),
+    Variable(String),
     Token(Token),
-    Queue(TokenQueue),
+    Constant(Constant),
+    Function(Builtin),
+    /// A function from the [`Scope`], with the number of arguments it takes.
+    ///
+    /// The name ends with a prime for every time it is differentiated, like `f'`.
+    UserFunction(String, usize),
+    /// `diff`, as in `diff(x^2,x)`.
+    Diff,
+    /// An operator like `d/dx`, differentiating with respect to the variable.
+    DerivativeOperator(String),
+    OpenParenthesis,
+    CloseParenthesis,
+    Comma,
 }
 
-impl From<TokenQueue> for QueueItem {
-    fn from(value: TokenQueue) -> Self {
-        QueueItem::Queue(value)
-    }
+/// Binding power of prefix `-` and `+`, placed between multiplication and exponentiation
+/// so that `-x^2` means `-(x^2)` while `-2x` means `(-2)*x`.
+const PREFIX_BINDING_POWER: u8 = 5;
+
+/// Precedence climbing parser turning a list of lexemes into an [`Expr`] tree.
+struct Parser {
+    lexemes: Peekable<IntoIter<(Lexeme, Span)>>,
+    /// Length of the input, used to point at the end of it when more input was expected.
+    end: usize,
+    limits: Limits,
+    /// How many expressions are currently being parsed inside each other.
+    depth: usize,
+    /// How many nodes the tree has so far.
+    nodes: usize,
 }
 
-impl From<f64> for QueueItem {
-    fn from(value: f64) -> Self {
-        QueueItem::Number(value)
+impl Parser {
+    fn parse(
+        lexemes: Vec<(Lexeme, Span)>,
+        end: usize,
+        limits: &Limits,
+    ) -> Result<Expr, ParseError> {
+        let mut parser = Self {
+            lexemes: lexemes.into_iter().peekable(),
+            end,
+            limits: *limits,
+            depth: 0,
+            nodes: 0,
+        };
+        let root = parser.expression(0)?;
+        if let Some((_, span)) = parser.lexemes.next() {
+            // Only a closing parenthesis or comma without a matching opening parenthesis can be left over.
+            return Err(ParseError::InvalidTokenPosition(span));
+        }
+        // Chains like `1+1+1` nest in the tree without nesting in the parser, so check the finished tree too.
+        if depth(&root) > limits.max_depth {
+            return Err(ParseError::TooDeeplyNested(limits.max_depth, 0..end));
+        }
+        Ok(root)
     }
-}
 
-impl From<Token> for QueueItem {
-    fn from(value: Token) -> Self {
-        QueueItem::Token(value)
+    fn end_span(&self) -> Span {
+        self.end..self.end
     }
-}
 
-impl TokenQueue {
-    fn push(&mut self, item: QueueItem) {
-        self.queue_items.push(item)
+    /// The span of the next lexeme, or the end of the input if there is none.
+    fn next_span(&mut self) -> Span {
+        match self.lexemes.peek() {
+            Some((_, span)) => span.clone(),
+            None => self.end_span(),
+        }
     }
 
-    pub fn new(input: &str, variables: &[String]) -> Result<Self, ParseError> {
-        let input = input.trim().replace(' ', "").to_lowercase();
-        let input = Self::add_parenthesis(&input);
-        let mut s = Self {
-            queue_items: Vec::new(),
-            input_representation: input.clone(),
-        };
+    /// Counts a new node of the tree, starting at `span`, failing if there are too many.
+    fn add_node(&mut self, span: Span) -> Result<(), ParseError> {
+        self.nodes += 1;
+        if self.nodes > self.limits.max_nodes {
+            Err(ParseError::TooLarge(self.limits.max_nodes, span))
+        } else {
+            Ok(())
+        }
+    }
 
-        let mut string_buff = String::new();
-
-        let mut chars = input.chars().peekable();
-        while let Some(mut c) = chars.next() {
-            if c == '(' {
-                let mut buffer = String::new();
-
-                let mut paren_count = 0;
-                // TODO: Add error handling for if no parentheses
-                while buffer.chars().filter(|b_c| b_c == &'(').count() + 1 > paren_count {
-                    if let Some(buff_char) = chars.next() {
-                        buffer.push(buff_char);
-                        if buff_char == ')' {
-                            paren_count += 1;
-                        }
-                    } else {
-                        return Err(ParseError::UnclosedParenthesis);
-                    }
-                }
-                buffer.pop();
-                s.push(Self::new(&buffer, variables)?.into());
-                if let Some(a) = chars.next() {
-                    c = a
+    /// Parses an expression where every infix operator binds at least as hard as `min_binding_power`.
+    fn expression(&mut self, min_binding_power: u8) -> Result<Expr, ParseError> {
+        if self.depth == self.limits.max_depth {
+            return Err(ParseError::TooDeeplyNested(
+                self.limits.max_depth,
+                self.next_span(),
+            ));
+        }
+        self.depth += 1;
+        let expr = self.nested_expression(min_binding_power);
+        self.depth -= 1;
+        expr
+    }
+
+    fn nested_expression(&mut self, min_binding_power: u8) -> Result<Expr, ParseError> {
+        let mut lhs = self.operand()?;
+        loop {
+            let (op, is_implicit) = match self.lexemes.peek() {
+                None | Some((Lexeme::CloseParenthesis | Lexeme::Comma, _)) => break,
+                Some((Lexeme::Token(token), _)) if token.binary_op().is_some() => {
+                    (token.binary_op().expect("Just checked"), false)
                 }
+                // Anything else starts a new operand, like in `2x` or `(x+1)(x-1)`.
+                Some(_) => (BinaryOp::Multiply, true),
+            };
+            let (left_binding_power, right_binding_power) = op.binding_power();
+            if left_binding_power < min_binding_power {
+                break;
             }
-            if let Some(number) = Self::get_next_number(&mut chars, c)? {
-                s.push(number.into())
+            let span = self.next_span();
+            self.add_node(span)?;
+            if !is_implicit {
+                self.lexemes.next();
             }
-            if let Some(op) = Token::new(&c.to_string()) {
-                s.push(op.into());
-            } else if c.is_alphabetic() {
-                string_buff.push(c);
-                while variables.iter().any(|var| var.starts_with(&string_buff)) {
-                    if let Some(op) = Token::new(&string_buff) {
-                        s.push(op.into());
-                        string_buff.clear();
-                        break;
-                    } else if variables.iter().any(|var| var.eq(&string_buff)) {
-                        s.push(QueueItem::Variable(string_buff.clone()));
-                        break;
-                    } else {
-                        string_buff.push(chars.next().ok_or(ParseError::UnableToFind(format!(
-                            "Variable {}",
-                            string_buff
-                        )))?);
-                    }
+            let rhs = self.expression(right_binding_power)?;
+            lhs = Expr::binary(op, lhs, rhs);
+        }
+        Ok(lhs)
+    }
+
+    fn operand(&mut self) -> Result<Expr, ParseError> {
+        let Some((lexeme, span)) = self.lexemes.next() else {
+            return Err(ParseError::UnableToParse(self.end_span()));
+        };
+        // Parentheses and a leading `+` add no node of their own.
+        if !matches!(lexeme, Lexeme::OpenParenthesis | Lexeme::Token(Token::Add)) {
+            self.add_node(span.clone())?;
+        }
+        match lexeme {
+            Lexeme::Number(num) => Ok(Expr::Number(num)),
+            Lexeme::Variable(var_name) => Ok(Expr::Variable(var_name)),
+            Lexeme::Token(Token::Subtract) => Ok(Expr::unary(
+                UnaryOp::Negate,
+                self.expression(PREFIX_BINDING_POWER)?,
+            )),
+            Lexeme::Token(Token::Add) => self.expression(PREFIX_BINDING_POWER),
+            Lexeme::Constant(constant) => Ok(Expr::Constant(constant)),
+            Lexeme::Function(builtin) => self.call(builtin.to_string(), builtin.arity(), span),
+            Lexeme::UserFunction(name, arity) => self.call(name, arity..=arity, span),
+            Lexeme::Diff => self.diff(),
+            Lexeme::DerivativeOperator(variable) => {
+                // Only a parenthesized expression, so it's clear how much of it is differentiated.
+                match self.lexemes.peek() {
+                    Some((Lexeme::OpenParenthesis, _)) => (),
+                    Some((_, span)) => return Err(ParseError::InvalidTokenPosition(span.clone())),
+                    None => return Err(ParseError::UnableToParse(self.end_span())),
+                }
+                let expr = self.operand()?;
+                Ok(Expr::Call(
+                    DIFF.to_string(),
+                    vec![expr, Expr::Variable(variable)],
+                ))
+            }
+            Lexeme::OpenParenthesis => {
+                let inner = self.expression(0)?;
+                match self.lexemes.next() {
+                    Some((Lexeme::CloseParenthesis, _)) => Ok(inner),
+                    Some((_, span)) => Err(ParseError::InvalidTokenPosition(span)),
+                    None => Err(ParseError::UnclosedParenthesis(span)),
                 }
-                string_buff.clear();
             }
+            _ => Err(ParseError::InvalidTokenPosition(span)),
+        }
+    }
+
+    /// Parses the parenthesized, comma separated arguments of a call to the function `name`,
+    /// which is at `name_span` and takes `arity` arguments.
+    fn call(
+        &mut self,
+        name: String,
+        arity: RangeInclusive<usize>,
+        name_span: Span,
+    ) -> Result<Expr, ParseError> {
+        let open_span = match self.lexemes.next() {
+            Some((Lexeme::OpenParenthesis, span)) => span,
+            Some((_, span)) => return Err(ParseError::InvalidTokenPosition(span)),
+            None => return Err(ParseError::UnableToParse(self.end_span())),
+        };
+        let mut args = vec![self.expression(0)?];
+        let close_span = loop {
+            match self.lexemes.next() {
+                Some((Lexeme::Comma, _)) => args.push(self.expression(0)?),
+                Some((Lexeme::CloseParenthesis, span)) => break span,
+                Some((_, span)) => return Err(ParseError::InvalidTokenPosition(span)),
+                None => return Err(ParseError::UnclosedParenthesis(open_span)),
+            }
+        };
+        if !arity.contains(&args.len()) {
+            return Err(ParseError::WrongArgumentCount(
+                name,
+                args.len(),
+                name_span.start..close_span.end,
+            ));
         }
-        Ok(s)
+        Ok(Expr::Call(name, args))
     }
-    fn add_parenthesis(input: &str) -> String {
-        Self::remove_parenthesis(&Self::add_most_basic_parenthesis(
-            &Self::add_parenthesis_multiplication(&Self::add_parenthesis_exponent(input)),
+
+    /// Parses the rest of `diff(expr,x)`, the derivative of `expr` with respect to the variable `x`.
+    fn diff(&mut self) -> Result<Expr, ParseError> {
+        let open_span = match self.lexemes.next() {
+            Some((Lexeme::OpenParenthesis, span)) => span,
+            Some((_, span)) => return Err(ParseError::InvalidTokenPosition(span)),
+            None => return Err(ParseError::UnableToParse(self.end_span())),
+        };
+        let expr = self.expression(0)?;
+        match self.lexemes.next() {
+            Some((Lexeme::Comma, _)) => (),
+            Some((_, span)) => return Err(ParseError::InvalidTokenPosition(span)),
+            None => return Err(ParseError::UnclosedParenthesis(open_span)),
+        }
+        let variable = match self.lexemes.next() {
+            Some((Lexeme::Variable(variable), _)) => variable,
+            Some((_, span)) => return Err(ParseError::InvalidTokenPosition(span)),
+            None => return Err(ParseError::UnableToParse(self.end_span())),
+        };
+        match self.lexemes.next() {
+            Some((Lexeme::CloseParenthesis, _)) => (),
+            Some((_, span)) => return Err(ParseError::InvalidTokenPosition(span)),
+            None => return Err(ParseError::UnclosedParenthesis(open_span)),
+        }
+        Ok(Expr::Call(
+            DIFF.to_string(),
+            vec![expr, Expr::Variable(variable)],
         ))
     }
+}
 
-    fn remove_parenthesis(input: &str) -> String {
-        let re = Regex::new(r#"\((\d+)\)"#).expect("Regex is valid");
-        re.replace_all(input, "$1").to_string()
+/// The depth of the deepest node in the tree, without recursing so any depth can be measured.
+fn depth(root: &Expr) -> usize {
+    let mut deepest = 0;
+    let mut unvisited = vec![(root, 1)];
+    while let Some((expr, depth)) = unvisited.pop() {
+        deepest = deepest.max(depth);
+        unvisited.extend(expr.children().into_iter().map(|child| (child, depth + 1)));
     }
+    deepest
+}
 
-    fn add_parenthesis_exponent(input: &str) -> String {
-        if !input.contains(['+', '-', '*', '/']) {
-            input.to_string()
-        } else {
-            let re = Regex::new(r#"(?:(\d+)|([a-z]+))\^(\d*[a-z]*\d*|\([^)]+\))"#)
-                .expect("Regex is valid");
-            re.replace_all(input, "($2^($3))").to_string()
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenQueue {
+    root: Expr,
+    pub input_representation: String,
+}
+
+impl TokenQueue {
+    /// Parses an expression over the given variables, within the default [`Limits`].
+    ///
+    /// Spans in returned errors are byte ranges into `input`.
+    pub fn new(input: &str, variables: &[String]) -> Result<Self, ParseError> {
+        Self::with_limits(input, variables, &Limits::default())
+    }
+
+    /// Parses an expression over the given variables, failing if it is larger than `limits` allow.
+    ///
+    /// Spans in returned errors are byte ranges into `input`.
+    pub fn with_limits(
+        input: &str,
+        variables: &[String],
+        limits: &Limits,
+    ) -> Result<Self, ParseError> {
+        Self::in_scope(input, variables, &Scope::new(), limits)
+    }
+
+    /// Parses an expression over the given variables that may call the functions in `scope`,
+    /// failing if it is larger than `limits` allow.
+    ///
+    /// Spans in returned errors are byte ranges into `input`.
+    pub fn in_scope(
+        input: &str,
+        variables: &[String],
+        scope: &Scope,
+        limits: &Limits,
+    ) -> Result<Self, ParseError> {
+        let root = Parser::parse(
+            Self::tokenize(input, variables, scope)?,
+            input.len(),
+            limits,
+        )?;
+        Ok(Self {
+            input_representation: root.to_string(),
+            root,
+        })
+    }
+
+    /// The names of the `variables` that `input` uses, found without parsing it any further
+    /// than splitting it into names.
+    pub(crate) fn variables_in(
+        input: &str,
+        variables: &[String],
+        scope: &Scope,
+    ) -> Result<Vec<String>, ParseError> {
+        let mut used = Vec::new();
+        for (lexeme, _) in Self::tokenize(input, variables, scope)? {
+            if let Lexeme::Variable(var_name) = lexeme {
+                if variables.contains(&var_name) && !used.contains(&var_name) {
+                    used.push(var_name);
+                }
+            }
         }
+        Ok(used)
+    }
+
+    fn tokenize(
+        input: &str,
+        variables: &[String],
+        scope: &Scope,
+    ) -> Result<Vec<(Lexeme, Span)>, ParseError> {
+        let mut lexemes = Vec::new();
+
+        let mut chars = input.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            let end = start + c.len_utf8();
+            if c.is_whitespace() {
+                continue;
+            } else if let Some((number, span)) = Self::get_next_number(&mut chars, start, c)? {
+                lexemes.push((Lexeme::Number(number), span));
+            } else if c == '(' {
+                lexemes.push((Lexeme::OpenParenthesis, start..end));
+            } else if c == ')' {
+                lexemes.push((Lexeme::CloseParenthesis, start..end));
+            } else if c == ',' {
+                lexemes.push((Lexeme::Comma, start..end));
+            } else if let Some(variable) =
+                Self::derivative_operator(&input[start..], variables, scope)
+            {
+                let end = start + "d/d".len() + variable.len();
+                while chars.next_if(|(i, _)| *i < end).is_some() {}
+                lexemes.push((Lexeme::DerivativeOperator(variable.to_string()), start..end));
+            } else if let Some(op) = Token::new(&c.to_string()) {
+                lexemes.push((Lexeme::Token(op), start..end));
+            } else if c == '\'' {
+                // A prime differentiates the function before it, like in `f'(x)`.
+                match lexemes.last_mut() {
+                    Some((Lexeme::UserFunction(name, _), span)) if span.end == start => {
+                        name.push('\'');
+                        span.end = end;
+                    }
+                    _ => return Err(ParseError::InvalidTokenPosition(start..end)),
+                }
+            } else if let Some((name, arity)) = Self::word_function(&input[start..], scope) {
+                // Functions can be named with any word, like `f1` or `F`, which isn't split into names.
+                let end = start + name.len();
+                while chars.next_if(|(i, _)| *i < end).is_some() {}
+                lexemes.push((Lexeme::UserFunction(name.to_string(), arity), start..end));
+            } else if c.is_alphabetic() {
+                let mut name = c.to_lowercase().to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| c.is_alphabetic()) {
+                    name.extend(c.to_lowercase());
+                }
+                lexemes.extend(Self::split_names(&name, start, variables, scope)?);
+            } else {
+                return Err(ParseError::UnableToParse(start..end));
+            }
+        }
+        Ok(lexemes)
     }
 
-    fn add_parenthesis_multiplication(input: &str) -> String {
-        if !input.contains(['+', '-']) {
-            input.to_string()
-        } else {
-            let re = Regex::new(r#"((\(.+\)|\d*[a-z]*\s*)[*/](\(.+\)|\d*[a-z]*))"#).unwrap();
-            re.replace_all(input, "($1)").to_string()
+    /// The variable of the derivative operator `input` starts with, like `x` in `d/dx(x^2)`.
+    ///
+    /// `d/dx` is only an operator if `x` is one of the `variables`, and `d` isn't a variable itself.
+    fn derivative_operator<'a>(
+        input: &'a str,
+        variables: &[String],
+        scope: &Scope,
+    ) -> Option<&'a str> {
+        let rest = input.strip_prefix("d/d")?;
+        let variable = &rest[..rest
+            .find(|c: char| !c.is_alphabetic())
+            .unwrap_or(rest.len())];
+        let is_variable =
+            |name: &str| variables.iter().any(|var| var == name) || scope.parameter(name).is_some();
+        (is_variable(variable) && !is_variable("d")).then_some(variable)
+    }
+
+    /// The function from `scope` whose name is the word `input` starts with,
+    /// if it's a name that can't be found by [`TokenQueue::split_names`] since it isn't just lowercase letters.
+    fn word_function<'a>(input: &'a str, scope: &Scope) -> Option<(&'a str, usize)> {
+        let word = &input[..input
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(input.len())];
+        if !word.starts_with(char::is_alphabetic) || word.chars().all(|c| c.is_lowercase()) {
+            return None;
         }
+        scope
+            .signatures()
+            .find(|(name, _)| *name == word)
+            .map(|(_, arity)| (word, arity))
     }
 
-    fn add_most_basic_parenthesis(input: &str) -> String {
-        let re = Regex::new(r#"([-+])([^()+\-]+)"#).expect("Regex is valid");
-        re.replace_all(input, "$1($2)").to_string()
+    /// Splits a run of letters like `tb` or `xsin`, starting at byte `start` of the input,
+    /// into the declared variables, constants and functions it consists of,
+    /// always preferring the longest name.
+    ///
+    /// Declared variables shadow everything else with the same name, then parameters from the scope,
+    /// and functions from the scope shadow constants and built-in functions.
+    fn split_names(
+        name: &str,
+        start: usize,
+        variables: &[String],
+        scope: &Scope,
+    ) -> Result<Vec<(Lexeme, Span)>, ParseError> {
+        let mut position = start;
+        let mut rest = name;
+        let mut found = Vec::new();
+        while !rest.is_empty() {
+            let candidates = variables
+                .iter()
+                .map(|var| (var.as_str(), Lexeme::Variable(var.clone())))
+                .chain(
+                    scope
+                        .parameters()
+                        .map(|name| (name, Lexeme::Variable(name.to_string()))),
+                )
+                .chain(
+                    scope
+                        .signatures()
+                        .map(|(name, arity)| (name, Lexeme::UserFunction(name.to_string(), arity))),
+                )
+                .chain(
+                    Constant::ALL
+                        .into_iter()
+                        .map(|constant| (constant.name(), Lexeme::Constant(constant))),
+                )
+                .chain(
+                    Builtin::ALL
+                        .into_iter()
+                        .map(|builtin| (builtin.name(), Lexeme::Function(builtin))),
+                )
+                .chain([(DIFF, Lexeme::Diff)])
+                .filter(|(name, _)| !name.is_empty() && rest.starts_with(name));
+
+            let mut longest: Option<(&str, Lexeme)> = None;
+            for (name, lexeme) in candidates {
+                // Only replace on strictly longer names, so earlier candidates win ties.
+                if longest
+                    .as_ref()
+                    .is_none_or(|(longest, _)| name.len() > longest.len())
+                {
+                    longest = Some((name, lexeme));
+                }
+            }
+            let (name, lexeme) = longest.ok_or_else(|| {
+                ParseError::UnknownVariable(rest.to_string(), position..position + rest.len())
+            })?;
+            found.push((lexeme, position..position + name.len()));
+            position += name.len();
+            rest = &rest[name.len()..];
+        }
+        Ok(found)
     }
 
     fn get_next_number(
-        chars: &mut Peekable<Chars>,
+        chars: &mut Peekable<CharIndices>,
+        start: usize,
         current_char: char,
-    ) -> Result<Option<f64>, ParseError> {
+    ) -> Result<Option<(f64, Span)>, ParseError> {
         if current_char.is_ascii_digit() {
             let mut has_gone_decimal = false;
             let mut num_buffer = String::from(current_char.to_owned());
-            while chars
-                .peek()
-                .is_some_and(|c| c.is_ascii_digit() || c == &'.')
-            {
-                let c = chars.next().expect("Already peeked forward");
+            while let Some((i, c)) = chars.next_if(|(_, c)| c.is_ascii_digit() || c == &'.') {
                 if c == '.' {
                     if has_gone_decimal {
-                        return Err(ParseError::DoubleDecimal);
+                        return Err(ParseError::DoubleDecimal(start..i + 1));
                     }
                     has_gone_decimal = true;
                 }
                 num_buffer.push(c)
             }
-            Ok(Some(num_buffer.parse().unwrap()))
+            let span = start..start + num_buffer.len();
+            Ok(Some((num_buffer.parse().unwrap(), span)))
         } else {
             Ok(None)
         }
     }
 
-    /// Gets the value of a variable from the variable map.
-    ///
-    /// # Arguments
-    ///
-    /// * `var_name` - The name of the variable to get the value of.
-    /// * `var_map` - A map of the function's variables to their values.
-    ///
-    /// # Returns
-    ///
-    /// * `Result<f64, ParseError>` - On success, the function returns `Ok(f64)`. On failure, it returns `Err(ParseError)`.
-    fn get_var_value(var_name: &str, var_map: &HashMap<String, f64>) -> Result<f64, ParseError> {
-        var_map
-            .get(var_name)
-            .copied()
-            .ok_or(ParseError::UnableToFind(format!(
-                "variable: \"{}\"",
-                var_name
-            )))
-    }
-
     /// Calculates the value of the function at the given x-value.
     ///
     /// # Arguments
@@ -313,113 +569,129 @@ impl TokenQueue {
     ///
     /// # Returns
     ///
-    /// * `Result<f64, ParseError>` - On success, the function returns `Ok(f64)`. On failure, it returns `Err(ParseError)`.
-    pub fn calculate(&self, var_map: &HashMap<String, f64>) -> Result<f64, ParseError> {
-        let previous_num: &mut Option<f64> = &mut None;
-
-        // Make the list of items peekable so we can look ahead without taking ownership of the upcoming value.
-        let mut list = self.queue_items.iter().peekable();
-        while let Some(item) = list.next() {
-            match item {
-                QueueItem::Variable(var_name) => {
-                    let mut var = Self::get_var_value(var_name, var_map)?;
-                    // Multiply the variable by any following variables or numbers.
-                    while let Some(QueueItem::Variable(_) | QueueItem::Number(_)) = list.peek() {
-                        match list.next() {
-                            Some(QueueItem::Variable(var_name)) => {
-                                var *= Self::get_var_value(var_name, var_map)?
-                            }
-                            Some(QueueItem::Number(num)) => var *= num,
-                            _ => unreachable!(),
-                        }
-                    }
+    /// * `Result<f64, EvalError>` - On success, the function returns `Ok(f64)`. On failure, it returns `Err(EvalError)`.
+    pub fn calculate(&self, var_map: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        self.root.evaluate(var_map)
+    }
 
-                    // If there is a previous number, multiply it by the current variable.
-                    // Otherwise, set the previous number to the current variable.
-                    if let Some(num) = previous_num {
-                        *num *= var;
-                    } else {
-                        *previous_num = Some(var)
-                    }
-                }
-                QueueItem::Number(num) => {
-                    if let Some(prev_num) = previous_num {
-                        *prev_num *= *num
-                    } else {
-                        *previous_num = Some(*num)
-                    }
-                }
-                QueueItem::Token(token) => match list.next() {
-                    Some(item) => match item {
-                        QueueItem::Token(..) => {
-                            // If there are two tokens (operations) in a row, return an error.
-                            return Err(ParseError::InvalidTokenPosition);
-                        }
-                        QueueItem::Variable(var_name) => {
-                            *previous_num = Some(
-                                token
-                                    .to_operation(
-                                        previous_num.ok_or(ParseError::InvalidTokenPosition)?,
-                                        *var_map.get(var_name).ok_or(ParseError::UnableToFind(
-                                            format!("variable \"{}\"", var_name),
-                                        ))?,
-                                    )
-                                    .do_operation(),
-                            );
-                        }
-                        QueueItem::Number(num2) => {
-                            *previous_num = Some(
-                                token
-                                    .to_operation(
-                                        previous_num.ok_or(ParseError::InvalidTokenPosition)?,
-                                        *num2,
-                                    )
-                                    .do_operation(),
-                            );
-                        }
-                        QueueItem::Queue(inner_queue) => {
-                            *previous_num = Some(
-                                token
-                                    .to_operation(
-                                        previous_num.ok_or(ParseError::InvalidTokenPosition)?,
-                                        inner_queue.calculate(var_map)?,
-                                    )
-                                    .do_operation(),
-                            )
-                        }
-                    },
-                    None => return Err(ParseError::UnableToFind("next item".to_string())),
-                },
-                // If the item is a queue, calculate the value of the queue and set/multiply depending on if previous number has been set.
-                QueueItem::Queue(q) => {
-                    if let Some(num) = previous_num {
-                        *num *= q.calculate(var_map)?
-                    } else {
-                        *previous_num = Some(q.calculate(var_map)?)
-                    }
-                }
-            }
-        }
-        previous_num.ok_or(ParseError::UnableToParse)
+    /// The parsed expression tree.
+    pub fn expr(&self) -> &Expr {
+        &self.root
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::parse::TokenQueue;
+    use crate::parse::{Limits, ParseError, TokenQueue};
     use std::collections::HashMap;
 
+    fn calculate_at(input: &str, x: f64) -> Result<f64, ParseError> {
+        Ok(TokenQueue::new(input, &["x".to_string()])?
+            .calculate(&HashMap::from([("x".to_string(), x)]))
+            .expect("Evaluation should succeed"))
+    }
+
     #[test]
-    fn test_add_parenthesis() {
-        assert_eq!(TokenQueue::add_parenthesis_multiplication("1*1"), "(1*1)");
-        assert_eq!(TokenQueue::add_parenthesis_multiplication("1/1"), "(1/1)");
+    fn test_precedence() {
+        let q = TokenQueue::new("2^(x+1)*3-x/(x+2)", &["x".to_string()]).unwrap();
+        assert_eq!(q.input_representation, "(((2^(x+1))*3)-(x/(x+2)))");
+        assert_eq!(calculate_at("2^(x+1)*3-x/(x+2)", 2.), Ok(23.5));
+        assert_eq!(calculate_at("1+2*3", 0.), Ok(7.));
+        assert_eq!(calculate_at("1*2+3", 0.), Ok(5.));
+        assert_eq!(calculate_at("5x^3+5", 1.), Ok(10.));
+    }
+
+    #[test]
+    fn test_associativity() {
+        assert_eq!(calculate_at("8-3-2", 0.), Ok(3.));
+        assert_eq!(calculate_at("16/4/2", 0.), Ok(2.));
+        assert_eq!(calculate_at("2^3^2", 0.), Ok(512.));
+    }
+
+    #[test]
+    fn test_nested_parenthesis() {
+        assert_eq!(calculate_at("((x+1)(x-1))/(2(x))", 3.), Ok(8. / 6.));
         assert_eq!(
-            TokenQueue::add_parenthesis_multiplication("1*1*1"),
-            "(1*1*1)"
+            calculate_at("(x+1", 0.),
+            Err(ParseError::UnclosedParenthesis(0..1))
         );
         assert_eq!(
-            TokenQueue::add_parenthesis_multiplication("1*1/20+12-17*20"),
-            "(1*1/20)+12-(17*20)"
+            calculate_at("x+1)", 0.),
+            Err(ParseError::InvalidTokenPosition(3..4))
+        );
+        assert_eq!(
+            calculate_at("x*/2", 0.),
+            Err(ParseError::InvalidTokenPosition(2..3))
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        assert_eq!(calculate_at(" 2 * x + 1", 1.), Ok(3.));
+        assert_eq!(
+            calculate_at("2x+1.2.3", 0.),
+            Err(ParseError::DoubleDecimal(3..7))
+        );
+        assert_eq!(
+            calculate_at("2x+ y", 0.),
+            Err(ParseError::UnknownVariable("y".to_string(), 4..5))
+        );
+        assert_eq!(
+            calculate_at("x+sin(x", 0.),
+            Err(ParseError::UnclosedParenthesis(5..6))
+        );
+    }
+
+    #[test]
+    fn test_unary() {
+        assert_eq!(calculate_at("-x^2", 3.), Ok(-9.));
+        assert_eq!(calculate_at("3*-x", 2.), Ok(-6.));
+        assert_eq!(calculate_at("(-2)^x", 3.), Ok(-8.));
+        assert_eq!(calculate_at("2^-x", 1.), Ok(0.5));
+        assert_eq!(calculate_at("-2x+-x", 1.), Ok(-3.));
+        assert_eq!(calculate_at("--x", 4.), Ok(4.));
+        assert_eq!(calculate_at("+x-+1", 4.), Ok(3.));
+        assert_eq!(
+            TokenQueue::new("-x^2", &["x".to_string()])
+                .unwrap()
+                .input_representation,
+            "(-(x^2))"
+        );
+        assert_eq!(calculate_at("x-", 0.), Err(ParseError::UnableToParse(2..2)));
+    }
+
+    #[test]
+    fn test_builtin_calls() {
+        assert_eq!(calculate_at("2cos(x)^2", 0.), Ok(2.));
+        assert_eq!(calculate_at("xabs(x-4)", 1.), Ok(3.));
+        assert_eq!(
+            calculate_at("sqrt(x,2)", 0.),
+            Err(ParseError::WrongArgumentCount("sqrt".to_string(), 2, 0..9))
+        );
+        assert_eq!(
+            calculate_at("sinx", 0.),
+            Err(ParseError::InvalidTokenPosition(3..4))
+        );
+        assert_eq!(
+            calculate_at("max(x,", 0.),
+            Err(ParseError::UnableToParse(6..6))
+        );
+    }
+
+    #[test]
+    fn test_constants() {
+        assert!(calculate_at("sin(pi*x)", 1.).unwrap().abs() < 1e-12);
+        assert_eq!(calculate_at("e^x", 1.), Ok(std::f64::consts::E));
+        assert_eq!(calculate_at("2pix", 1.), Ok(std::f64::consts::TAU));
+        assert_eq!(
+            calculate_at("tau-exp(x)", 0.),
+            Ok(std::f64::consts::TAU - 1.)
+        );
+
+        let shadowed = TokenQueue::new("e^2", &["e".to_string()]).unwrap();
+        assert_eq!(
+            shadowed.calculate(&HashMap::from([("e".to_string(), 3.)])),
+            Ok(9.)
         );
     }
 
@@ -427,7 +699,7 @@ mod test {
     fn test_queue() {
         let q = TokenQueue::new("t^2", &["t".to_string()]).unwrap();
         dbg!(&q);
-        dbg!(q.calculate(&HashMap::from([("t".to_string(), 1.)])));
+        assert_eq!(q.calculate(&HashMap::from([("t".to_string(), 2.)])), Ok(4.));
     }
 
     #[test]
@@ -438,4 +710,50 @@ mod test {
             Ok(10.)
         );
     }
+
+    #[test]
+    fn test_limits() {
+        let x = ["x".to_string()];
+        let limits = Limits::default();
+        let deep = format!("{}x{}", "(".repeat(10_000), ")".repeat(10_000));
+        assert_eq!(
+            TokenQueue::new(&deep, &x),
+            Err(ParseError::TooDeeplyNested(
+                limits.max_depth,
+                limits.max_depth..limits.max_depth + 1
+            ))
+        );
+        let towers = format!("x{}", "^x".repeat(10_000));
+        assert!(matches!(
+            TokenQueue::new(&towers, &x),
+            Err(ParseError::TooDeeplyNested(..))
+        ));
+
+        // A long sum is no problem for the parser, but makes a deep tree.
+        let sum = format!("x{}", "+1".repeat(limits.max_depth));
+        assert_eq!(
+            TokenQueue::new(&sum, &x),
+            Err(ParseError::TooDeeplyNested(limits.max_depth, 0..sum.len()))
+        );
+        let sum = format!("x{}", "+1".repeat(limits.max_nodes));
+        assert!(matches!(
+            TokenQueue::new(&sum, &x),
+            Err(ParseError::TooLarge(max, _)) if max == limits.max_nodes
+        ));
+
+        let small = Limits {
+            max_depth: 3,
+            max_nodes: 5,
+            ..Default::default()
+        };
+        assert!(TokenQueue::with_limits("(x+1)*2", &x, &small).is_ok());
+        assert_eq!(
+            TokenQueue::with_limits("(((x)))", &x, &small),
+            Err(ParseError::TooDeeplyNested(3, 3..4))
+        );
+        assert_eq!(
+            TokenQueue::with_limits("x+x+x+x", &x, &small),
+            Err(ParseError::TooLarge(5, 5..6))
+        );
+    }
 }